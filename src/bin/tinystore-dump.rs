@@ -0,0 +1,193 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tinystore::store::{Inspector, ItemValue, PageDump, PageKind};
+
+const USAGE: &str = "\
+Usage: tinystore-dump <database> [--page <id> | --subtree <id> | --all] [--hex]
+
+Prints the metadata header and a one line summary of every page,
+or the full contents of the chosen pages
+
+  --page <id>     dump a single page
+  --subtree <id>  dump a page and every page below it
+  --all           dump every page in the file
+  --hex           print keys and values as hex, even if valid UTF-8";
+
+enum Selection {
+    Summary,
+    Page(u32),
+    Subtree(u32),
+    All,
+}
+
+struct Args {
+    path: PathBuf,
+    selection: Selection,
+    hex: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut path = None;
+    let mut selection = Selection::Summary;
+    let mut hex = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut page_id = || {
+            let id = args.next().ok_or(format!("{arg} needs a page id"))?;
+            id.parse::<u32>()
+                .map_err(|_| format!("Invalid page id {id:?}"))
+        };
+
+        match arg.as_str() {
+            "--page" => selection = Selection::Page(page_id()?),
+            "--subtree" => selection = Selection::Subtree(page_id()?),
+            "--all" => selection = Selection::All,
+            "--hex" => hex = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+
+    Ok(Args {
+        path: path.ok_or("Missing database path")?,
+        selection,
+        hex,
+    })
+}
+
+///
+/// Quoted UTF-8 when printable, hex otherwise
+///
+fn format_bytes(bytes: &[u8], hex: bool) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !hex && !text.chars().any(char::is_control) => format!("{text:?}"),
+        _ => {
+            let digits: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            format!("0x{digits}")
+        }
+    }
+}
+
+fn kind_name(kind: PageKind) -> &'static str {
+    match kind {
+        PageKind::Meta => "meta",
+        PageKind::Internal => "internal",
+        PageKind::Leaf => "leaf",
+        PageKind::Overflow => "overflow",
+        PageKind::Free => "free",
+        PageKind::Unreachable => "unreachable",
+    }
+}
+
+fn print_summary(page: &PageDump) {
+    let items = match page.kind {
+        PageKind::Overflow => format!("chunk {}", page.n_items),
+        _ => format!("items {}", page.n_items),
+    };
+    let checksum = if page.checksum_ok {
+        ""
+    } else {
+        "  BAD CHECKSUM"
+    };
+
+    let line = format!(
+        "page {:<6} {:<11} {:<11} free {:<5} prev {:<6} next {:<6}{checksum}",
+        page.id,
+        kind_name(page.kind),
+        items,
+        page.free,
+        page.prev,
+        page.next,
+    );
+    println!("{}", line.trim_end());
+}
+
+fn print_page(page: &PageDump, hex: bool) {
+    print_summary(page);
+
+    if let Some(slot) = page.bad_slot {
+        println!("  slot {slot} points outside the page, items not decoded");
+    }
+
+    for (key, value) in &page.items {
+        let value = match value {
+            ItemValue::Child(pid) => format!("child {pid}"),
+            ItemValue::Inline(value) => format_bytes(value, hex),
+            ItemValue::Overflow { prefix, len, head } => format!(
+                "overflow of {len} bytes from page {head}, prefix {}",
+                format_bytes(prefix, hex)
+            ),
+            ItemValue::Invalid(value) => format!("invalid {}", format_bytes(value, true)),
+        };
+        println!("  {} => {value}", format_bytes(key, hex));
+    }
+
+    if page.kind == PageKind::Overflow {
+        println!("  {}", format_bytes(&page.chunk, hex));
+    }
+}
+
+fn run(args: Args) -> tinystore::Result<()> {
+    let inspector = Inspector::open(&args.path)?;
+    let header = inspector.header();
+    let n_pages = inspector.n_pages()?;
+
+    println!("version     {}", header.version);
+    println!("size        {} ({n_pages} pages in file)", header.size);
+    println!("root        {}", header.root);
+    println!("height      {}", header.height);
+    println!("free head   {}", header.free_head);
+    println!("free pages  {}", header.free_pages);
+
+    let mut wal = args.path.clone().into_os_string();
+    wal.push("-wal");
+    if std::fs::metadata(&wal).is_ok_and(|meta| meta.len() > 0) {
+        println!("write ahead log present, commits in it aren't shown");
+    }
+    println!();
+
+    match args.selection {
+        Selection::Summary => {
+            for pid in 1..n_pages {
+                print_summary(&inspector.page(pid)?);
+            }
+        }
+        Selection::Page(pid) => print_page(&inspector.page(pid)?, args.hex),
+        Selection::Subtree(pid) => {
+            for pid in inspector.subtree(pid)? {
+                print_page(&inspector.page(pid)?, args.hex);
+            }
+        }
+        Selection::All => {
+            for pid in 1..n_pages {
+                print_page(&inspector.page(pid)?, args.hex);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("{err}\n");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("tinystore-dump: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
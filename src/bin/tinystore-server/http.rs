@@ -0,0 +1,247 @@
+use std::io::{self, BufRead, Read, Write};
+
+///
+/// Longest request line or header block accepted
+///
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    ///
+    /// Plain text explanation of an error
+    /// status, ending in a newline
+    ///
+    pub fn error(status: u16, message: impl std::fmt::Display) -> Response {
+        Response::new(status, format!("{message}\n"))
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Response {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+///
+/// Reads the next request off a connection, None once the
+/// client closes it between requests
+///
+/// Malformed requests come back as the response to send
+/// before closing the connection
+///
+pub fn read_request(
+    reader: &mut impl BufRead,
+    max_body: usize,
+) -> io::Result<Result<Option<Request>, Response>> {
+    let mut budget = MAX_HEADER_SIZE;
+    let Some(line) = read_line(reader, &mut budget)? else {
+        return Ok(Ok(None));
+    };
+    let line = match line {
+        Ok(line) => line,
+        Err(response) => return Ok(Err(response)),
+    };
+
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(Err(Response::error(400, "Malformed request line")));
+    };
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Ok(Err(Response::error(505, "Only HTTP/1.x is supported")));
+    }
+
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_length = None;
+    loop {
+        let line = match read_line(reader, &mut budget)? {
+            Some(Ok(line)) => line,
+            Some(Err(response)) => return Ok(Err(response)),
+            None => return Ok(Err(Response::error(400, "Truncated headers"))),
+        };
+        if line.is_empty() {
+            break;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok(Err(Response::error(400, "Malformed header")));
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(len) => content_length = Some(len),
+                Err(_) => return Ok(Err(Response::error(400, "Invalid Content-Length"))),
+            },
+            "transfer-encoding" => {
+                return Ok(Err(Response::error(
+                    501,
+                    "Transfer-Encoding isn't supported",
+                )))
+            }
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+
+    let len = match (method, content_length) {
+        ("PUT", None) => return Ok(Err(Response::error(411, "PUT needs a Content-Length"))),
+        (_, len) => len.unwrap_or(0),
+    };
+    if len > max_body {
+        let message = format!("Body of {len} bytes exceeds the limit of {max_body} bytes");
+        return Ok(Err(Response::error(413, message)));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        body,
+        keep_alive,
+    })))
+}
+
+///
+/// Reads a CRLF terminated line within what's left of the
+/// header budget, None if the stream ended before it started
+///
+fn read_line(
+    reader: &mut impl BufRead,
+    budget: &mut usize,
+) -> io::Result<Option<Result<String, Response>>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(*budget as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > *budget {
+        return Ok(Some(Err(Response::error(431, "Request headers too large"))));
+    }
+    *budget -= read;
+
+    if line.pop() != Some(b'\n') {
+        return Ok(Some(Err(Response::error(400, "Truncated request"))));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(Ok(line))),
+        Err(_) => Ok(Some(Err(Response::error(400, "Headers must be UTF-8")))),
+    }
+}
+
+///
+/// Decodes %XX escapes into raw bytes, so
+/// keys don't have to be valid UTF-8
+///
+pub fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(out)
+}
+
+///
+/// Escapes everything but unreserved URL characters, the
+/// result can be used as is in a /kv/{key} path
+///
+pub fn percent_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+
+    out
+}
+
+///
+/// Splits a query string into decoded name, value pairs
+///
+pub fn parse_query(query: &str) -> Option<Vec<(String, Vec<u8>)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8(percent_decode(&name.replace('+', " "))?).ok()?;
+            Some((name, percent_decode(&value.replace('+', " "))?))
+        })
+        .collect()
+}
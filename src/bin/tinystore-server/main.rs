@@ -0,0 +1,318 @@
+mod http;
+mod resp;
+
+use http::{parse_query, percent_decode, percent_encode, Request, Response};
+use log::{info, warn};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tinystore::store::{Connection, Database};
+use tinystore::Error;
+
+const USAGE: &str = "\
+Usage: tinystore-server <database> [--resp] [--listen <addr>] [--max-body <bytes>]
+
+Serves the database over HTTP:
+
+  GET    /kv/{key}                                 value stored under key
+  PUT    /kv/{key}                                 store the request body
+  DELETE /kv/{key}                                 remove the entry
+  GET    /kv?prefix=&start=&end=&limit=            entries in range
+
+Keys are percent encoded in paths and listings, which hold one
+<key>\\t<value> line per entry with both percent encoded
+
+With --resp it speaks the redis protocol (RESP2) instead, taking
+GET, SET, DEL, EXISTS, SCAN, MGET, MSET, PING and QUIT
+
+  --resp              serve the redis protocol rather than HTTP
+  --listen <addr>     address to listen on (default 127.0.0.1:7878,
+                      or 127.0.0.1:6379 with --resp)
+  --max-body <bytes>  largest request body or bulk string accepted
+                      (default 16MiB)";
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+const DEFAULT_RESP_LISTEN: &str = "127.0.0.1:6379";
+const DEFAULT_MAX_BODY: usize = 16 << 20;
+///
+/// Entries returned by a listing without a limit
+///
+const DEFAULT_LIMIT: usize = 1000;
+///
+/// How long and how much is read from a
+/// rejected client before hanging up
+///
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+const LINGER_LIMIT: u64 = 64 << 20;
+
+struct Args {
+    path: PathBuf,
+    listen: String,
+    max_body: usize,
+    resp: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut path = None;
+    let mut listen = None;
+    let mut max_body = DEFAULT_MAX_BODY;
+    let mut resp = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+
+        match arg.as_str() {
+            "--listen" => listen = Some(value()?),
+            "--resp" => resp = true,
+            "--max-body" => {
+                let bytes = value()?;
+                max_body = bytes
+                    .parse()
+                    .map_err(|_| format!("Invalid body size {bytes:?}"))?;
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+
+    let default_listen = if resp {
+        DEFAULT_RESP_LISTEN
+    } else {
+        DEFAULT_LISTEN
+    };
+    Ok(Args {
+        path: path.ok_or("Missing database path")?,
+        listen: listen.unwrap_or_else(|| default_listen.to_string()),
+        max_body,
+        resp,
+    })
+}
+
+///
+/// Maps store errors onto status codes, anything
+/// other than a bad key is the server's fault
+///
+fn store_error(err: Error) -> Response {
+    match err {
+        Error::KeyTooLarge { .. } => Response::error(400, err),
+        Error::NotFound => Response::error(404, err),
+        _ => {
+            warn!("Request failed: {err}");
+            Response::error(500, err)
+        }
+    }
+}
+
+fn handle(request: &Request, db: &Database) -> Response {
+    if let Some(key) = request.path.strip_prefix("/kv/") {
+        let Some(key) = percent_decode(key) else {
+            return Response::error(400, "Malformed percent encoding in key");
+        };
+
+        let result = match request.method.as_str() {
+            "GET" => db.get(&key).map(|value| match value {
+                Some(value) => {
+                    Response::new(200, value).header("Content-Type", "application/octet-stream")
+                }
+                None => Response::error(404, "Key not found"),
+            }),
+            "PUT" => db.put(&key, &request.body).map(|_| Response::new(204, "")),
+            "DELETE" => db.delete(&key).map(|removed| match removed {
+                true => Response::new(204, ""),
+                false => Response::error(404, "Key not found"),
+            }),
+            _ => Ok(Response::error(405, "Method not allowed").header("Allow", "GET, PUT, DELETE")),
+        };
+
+        return result.unwrap_or_else(store_error);
+    }
+
+    if request.path == "/kv" {
+        if request.method != "GET" {
+            return Response::error(405, "Method not allowed").header("Allow", "GET");
+        }
+        return list(&db.read(), &request.query);
+    }
+
+    Response::error(404, "No such resource")
+}
+
+///
+/// Lists entries within the prefix and start (inclusive) to
+/// end (exclusive) range, at most limit of them
+///
+/// A truncated listing says where to start the next one
+///
+fn list(connection: &Connection, query: &str) -> Response {
+    let Some(params) = parse_query(query) else {
+        return Response::error(400, "Malformed query string");
+    };
+
+    let (mut prefix, mut start, mut end) = (Vec::new(), None, None);
+    let mut limit = DEFAULT_LIMIT;
+    for (name, value) in params {
+        match name.as_str() {
+            "prefix" => prefix = value,
+            "start" => start = Some(value),
+            "end" => end = Some(value),
+            "limit" => match std::str::from_utf8(&value)
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                Some(value) => limit = value,
+                None => return Response::error(400, "Invalid limit"),
+            },
+            _ => return Response::error(400, format!("Unknown parameter {name}")),
+        }
+    }
+
+    let start = match start {
+        Some(start) => Bound::Included(start.max(prefix.clone())),
+        None => Bound::Included(prefix.clone()),
+    };
+    let end = match end {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+
+    let mut body = Vec::new();
+    let mut next = None;
+    let cursor = match connection.range((start, end)) {
+        Ok(cursor) => cursor,
+        Err(err) => return store_error(err),
+    };
+    for (n, entry) in cursor.enumerate() {
+        let (key, value) = match entry {
+            Ok(entry) => entry,
+            Err(err) => return store_error(err),
+        };
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        if n == limit {
+            next = Some(key);
+            break;
+        }
+
+        body.extend_from_slice(percent_encode(&key).as_bytes());
+        body.push(b'\t');
+        body.extend_from_slice(percent_encode(&value).as_bytes());
+        body.push(b'\n');
+    }
+
+    let response = Response::new(200, body).header("Content-Type", "text/plain; charset=utf-8");
+    match next {
+        Some(key) => response.header("X-Next-Start", percent_encode(&key)),
+        None => response,
+    }
+}
+
+///
+/// Serves requests on one client connection
+/// until either side closes it
+///
+fn serve(stream: TcpStream, db: &Database, max_body: usize) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match http::read_request(&mut reader, max_body)? {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(response) => {
+                response.write_to(&mut writer, false)?;
+                return linger(reader);
+            }
+        };
+
+        let response = handle(&request, db);
+        response.write_to(&mut writer, request.keep_alive)?;
+        if !request.keep_alive {
+            return writer.flush();
+        }
+    }
+}
+
+///
+/// Closes after a rejected request, reading what the client still
+/// sends so closing with unread data doesn't reset the connection
+/// before it gets the response
+///
+fn linger(mut reader: BufReader<TcpStream>) -> std::io::Result<()> {
+    let stream = reader.get_ref();
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(LINGER_TIMEOUT))?;
+    std::io::copy(
+        &mut reader.by_ref().take(LINGER_LIMIT),
+        &mut std::io::sink(),
+    )?;
+
+    Ok(())
+}
+
+fn run(args: Args) -> tinystore::Result<()> {
+    let db = Database::open(&args.path)?;
+    let listener = TcpListener::bind(&args.listen)?;
+
+    // Printed for scripts binding to port 0
+    println!("Listening on {}", listener.local_addr()?);
+    std::io::stdout().flush()?;
+    info!(
+        "Serving {} on {}",
+        args.path.display(),
+        listener.local_addr()?
+    );
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept connection: {err}");
+                continue;
+            }
+        };
+
+        let db = db.clone();
+        let (max_body, resp) = (args.max_body, args.resp);
+        std::thread::spawn(move || {
+            let served = match resp {
+                true => resp::serve(stream, &db, max_body),
+                false => serve(stream, &db, max_body),
+            };
+            if let Err(err) = served {
+                info!("Client connection closed: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("{err}\n");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("tinystore-server: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use log::warn;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use tinystore::store::{Connection, Database};
+
+///
+/// Longest inline command or length line accepted
+///
+const MAX_LINE_SIZE: usize = 64 * 1024;
+///
+/// Most arguments a single command can have
+///
+const MAX_ARGS: usize = 1024 * 1024;
+///
+/// Keys looked at by a SCAN call without a COUNT
+///
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: impl std::fmt::Display) -> Reply {
+        Reply::Error(format!("ERR {message}"))
+    }
+
+    fn arity(name: &str) -> Reply {
+        Reply::error(format!(
+            "wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ))
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{status}\r\n"),
+            Reply::Error(message) => {
+                // Line breaks would end the error early
+                let message = message.replace(['\r', '\n'], " ");
+                write!(writer, "-{message}\r\n")
+            }
+            Reply::Integer(n) => write!(writer, ":{n}\r\n"),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(writer))
+            }
+        }
+    }
+}
+
+///
+/// Reads the next command, either a RESP array of bulk strings
+/// or an inline command, None once the client closes the connection
+///
+/// Malformed input comes back as the error to send
+/// before closing the connection
+///
+pub fn read_command(
+    reader: &mut impl BufRead,
+    max_bulk: usize,
+) -> io::Result<Result<Option<Vec<Vec<u8>>>, Reply>> {
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Ok(Ok(None));
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(reply) => return Ok(Err(reply)),
+        };
+
+        let Some(count) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            // Blank lines are skipped, like redis does
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Ok(Some(args)));
+        };
+
+        let count = match parse_length(count) {
+            Some(count) if count <= MAX_ARGS => count,
+            _ => {
+                return Ok(Err(Reply::error(
+                    "Protocol error: invalid multibulk length",
+                )))
+            }
+        };
+        if count == 0 {
+            continue;
+        }
+
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let line = match read_line(reader)? {
+                Some(Ok(line)) => line,
+                Some(Err(reply)) => return Ok(Err(reply)),
+                None => return Ok(Err(Reply::error("Protocol error: truncated command"))),
+            };
+            let Some(len) = line.strip_prefix(b"$") else {
+                return Ok(Err(Reply::error("Protocol error: expected '$'")));
+            };
+            let len = match parse_length(len) {
+                Some(len) if len <= max_bulk => len,
+                _ => return Ok(Err(Reply::error("Protocol error: invalid bulk length"))),
+            };
+
+            let mut arg = vec![0u8; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Ok(Err(Reply::error("Protocol error: expected CRLF")));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+
+        return Ok(Ok(Some(args)));
+    }
+}
+
+fn parse_length(digits: &[u8]) -> Option<usize> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+///
+/// Reads a CRLF terminated line, None if the
+/// stream ended before it started
+///
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Result<Vec<u8>, Reply>>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > MAX_LINE_SIZE {
+        return Ok(Some(Err(Reply::error(
+            "Protocol error: too big inline request",
+        ))));
+    }
+
+    if line.pop() != Some(b'\n') {
+        return Ok(Some(Err(Reply::error("Protocol error: truncated command"))));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(Ok(line)))
+}
+
+///
+/// Runs a single command against the database, None
+/// when the client asked to close the connection
+///
+pub fn execute(args: &[Vec<u8>], db: &Database) -> Option<Reply> {
+    let command = String::from_utf8_lossy(&args[0]);
+    let name = command.to_ascii_uppercase();
+    let args = &args[1..];
+
+    let result = match (name.as_str(), args) {
+        ("PING", []) => Ok(Reply::Status("PONG")),
+        ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        ("QUIT", _) => return None,
+        ("GET", [key]) => db.get(key).map(Reply::Bulk),
+        ("SET", [key, value, options @ ..]) => set(&mut db.write(), key, value, options),
+        ("MGET", [_, ..]) => {
+            let connection = db.read();
+            args.iter()
+                .map(|key| connection.get(key).map(Reply::Bulk))
+                .collect::<tinystore::Result<_>>()
+                .map(Reply::Array)
+        }
+        ("MSET", [_, _, ..]) if args.len().is_multiple_of(2) => {
+            let mut connection = db.write();
+            let mut txn = connection.begin();
+            args.chunks(2)
+                .try_for_each(|pair| txn.put(&pair[0], &pair[1]))
+                .and_then(|_| txn.commit())
+                .map(|_| Reply::Status("OK"))
+        }
+        ("DEL", [_, ..]) => {
+            let mut connection = db.write();
+            let mut txn = connection.begin();
+            let mut removed = 0;
+            args.iter()
+                .try_for_each(|key| {
+                    removed += txn.delete(key)? as i64;
+                    Ok(())
+                })
+                .and_then(|_| txn.commit())
+                .map(|_| Reply::Integer(removed))
+        }
+        ("EXISTS", [_, ..]) => {
+            let connection = db.read();
+            let mut found = 0;
+            args.iter()
+                .try_for_each(|key| {
+                    found += connection.get(key)?.is_some() as i64;
+                    Ok(())
+                })
+                .map(|_| Reply::Integer(found))
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(&db.read(), cursor, options),
+        ("PING" | "GET" | "SET" | "MGET" | "MSET" | "DEL" | "EXISTS" | "SCAN", _) => {
+            Ok(Reply::arity(&name))
+        }
+        _ => Ok(Reply::error(format!("unknown command '{command}'"))),
+    };
+
+    Some(result.unwrap_or_else(|err| {
+        if !matches!(err, tinystore::Error::KeyTooLarge { .. }) {
+            warn!("Command {name} failed: {err}");
+        }
+        Reply::error(err)
+    }))
+}
+
+///
+/// SET with the NX (only if missing) and
+/// XX (only if present) conditions
+///
+fn set(
+    connection: &mut Connection,
+    key: &Vec<u8>,
+    value: &Vec<u8>,
+    options: &[Vec<u8>],
+) -> tinystore::Result<Reply> {
+    let (mut nx, mut xx) = (false, false);
+    for option in options {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            _ => return Ok(Reply::error("syntax error")),
+        }
+    }
+    if nx && xx {
+        return Ok(Reply::error("syntax error"));
+    }
+
+    if nx || xx {
+        let exists = connection.get(key)?.is_some();
+        if exists == nx {
+            return Ok(Reply::Bulk(None));
+        }
+    }
+    connection.put(key, value)?;
+
+    Ok(Reply::Status("OK"))
+}
+
+///
+/// SCAN over every key, the cursor encodes the last key
+/// looked at, or is 0 to start, so each call seeks straight
+/// past it and deleted keys don't throw later calls off
+///
+fn scan(connection: &Connection, cursor: &[u8], options: &[Vec<u8>]) -> tinystore::Result<Reply> {
+    let Some(start) = parse_cursor(cursor) else {
+        return Ok(Reply::error("invalid cursor"));
+    };
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next();
+        match (option.to_ascii_uppercase().as_slice(), value) {
+            (b"MATCH", Some(value)) => pattern = Some(value),
+            (b"COUNT", Some(value)) => match parse_length(value) {
+                Some(n) if n > 0 => count = n,
+                _ => return Ok(Reply::error("value is not an integer or out of range")),
+            },
+            _ => return Ok(Reply::error("syntax error")),
+        }
+    }
+
+    let mut keys = Vec::new();
+    let mut last = Vec::new();
+    let mut next = b"0".to_vec();
+    for (n, entry) in connection.range((start, Bound::Unbounded))?.enumerate() {
+        let (key, _) = entry?;
+        if n == count {
+            next = encode_cursor(&last);
+            break;
+        }
+        if pattern.is_none_or(|pattern| glob_match(pattern, &key)) {
+            keys.push(Reply::Bulk(Some(key.clone())));
+        }
+        last = key;
+    }
+
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next)),
+        Reply::Array(keys),
+    ]))
+}
+
+///
+/// Cursors have to be unsigned integers clients can parse, so
+/// the key is written as a 1 followed by three decimal digits
+/// per byte, the lead digit keeps leading zeros in the key
+///
+fn encode_cursor(key: &[u8]) -> Vec<u8> {
+    let mut cursor = b"1".to_vec();
+    cursor.extend(
+        key.iter()
+            .flat_map(|byte| format!("{byte:03}").into_bytes()),
+    );
+
+    cursor
+}
+
+///
+/// Where a SCAN picks up, 0 starts from the first key
+///
+fn parse_cursor(cursor: &[u8]) -> Option<Bound<Vec<u8>>> {
+    if cursor == b"0" {
+        return Some(Bound::Unbounded);
+    }
+    let digits = cursor.strip_prefix(b"1")?;
+    if !digits.len().is_multiple_of(3) || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    digits
+        .chunks(3)
+        .map(|digits| std::str::from_utf8(digits).ok()?.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()
+        .map(Bound::Excluded)
+}
+
+///
+/// Redis style glob: * and ? wildcards, [abc], [^a] and [a-z]
+/// classes, with backslash escaping the next character
+///
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, text)) = text.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => return false,
+                    [b']', rest @ ..] => {
+                        class = rest;
+                        break;
+                    }
+                    [b'\\', escaped, rest @ ..] => {
+                        matched |= *escaped == c;
+                        class = rest;
+                    }
+                    [low, b'-', high, rest @ ..] if *high != b']' => {
+                        let (low, high) = (*low.min(high), *low.max(high));
+                        matched |= (low..=high).contains(&c);
+                        class = rest;
+                    }
+                    [single, rest @ ..] => {
+                        matched |= *single == c;
+                        class = rest;
+                    }
+                }
+            }
+
+            matched != negate && glob_match(class, text)
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+///
+/// Serves commands on one client connection
+/// until either side closes it
+///
+pub fn serve(stream: TcpStream, db: &Database, max_bulk: usize) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader, max_bulk)? {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(reply) => {
+                reply.write_to(&mut writer)?;
+                writer.flush()?;
+                return super::linger(reader);
+            }
+        };
+
+        let Some(reply) = execute(&args, db) else {
+            Reply::Status("OK").write_to(&mut writer)?;
+            return writer.flush();
+        };
+        reply.write_to(&mut writer)?;
+
+        // Pipelined commands get their replies in one go
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
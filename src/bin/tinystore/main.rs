@@ -0,0 +1,342 @@
+mod encoding;
+
+use encoding::Encoding;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tinystore::store::Connection;
+
+const USAGE: &str = "\
+Usage: tinystore <database> <command> [args] [--encoding <utf8|hex|base64>]
+
+Commands:
+  get <key>                 print the value stored under key
+  put <key> <value>         store a single entry
+  put <key>                 store stdin as the value of key
+  put                       store every <key>\\t<value> line read from stdin
+  delete <key>              remove a single entry
+  delete                    remove every key read from stdin, one per line
+  scan [range]              print every entry in range as <key>\\t<value>
+  count [range]             print the number of entries in range
+  stats                     print file size, page and entry counts
+
+Ranges:
+  --prefix <prefix>         keys starting with prefix
+  --from <key>              keys from key on, inclusive
+  --to <key>                keys before key, exclusive
+
+Keys and values are read and printed as UTF-8 unless --encoding
+says otherwise, bulk input from stdin is applied in one transaction";
+
+type Entry = (Vec<u8>, Vec<u8>);
+type Range = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+enum Command {
+    Get(String),
+    Put(Option<String>, Option<String>),
+    Delete(Option<String>),
+    Scan,
+    Count,
+    Stats,
+}
+
+struct Args {
+    path: PathBuf,
+    command: Command,
+    encoding: Encoding,
+    prefix: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+///
+/// Failure reported on exit, usage errors print
+/// the usage text after their message
+///
+enum Failure {
+    Usage(String),
+    NotFound,
+    Error(String),
+}
+
+impl<E: std::fmt::Display> From<E> for Failure {
+    fn from(err: E) -> Failure {
+        Failure::Error(err.to_string())
+    }
+}
+
+fn parse_args() -> Result<Args, Failure> {
+    let mut positional = Vec::new();
+    let mut encoding = Encoding::Utf8;
+    let (mut prefix, mut from, mut to) = (None, None, None);
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| Failure::Usage(format!("{arg} needs a value")))
+        };
+
+        match arg.as_str() {
+            "-e" | "--encoding" => {
+                let name = value()?;
+                encoding = Encoding::parse(&name)
+                    .ok_or_else(|| Failure::Usage(format!("Unknown encoding {name}")))?;
+            }
+            "--prefix" => prefix = Some(value()?),
+            "--from" => from = Some(value()?),
+            "--to" => to = Some(value()?),
+            "-h" | "--help" => return Err(Failure::Usage(String::new())),
+            _ if arg.starts_with("--") => {
+                return Err(Failure::Usage(format!("Unknown option {arg}")))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let path = positional
+        .next()
+        .ok_or_else(|| Failure::Usage("Missing database path".to_string()))?;
+    let name = positional
+        .next()
+        .ok_or_else(|| Failure::Usage("Missing command".to_string()))?;
+    let mut rest: Vec<String> = positional.collect();
+
+    let max_args = match name.as_str() {
+        "get" => 1,
+        "put" => 2,
+        "delete" => 1,
+        _ => 0,
+    };
+    if rest.len() > max_args {
+        return Err(Failure::Usage(format!("Too many arguments for {name}")));
+    }
+    let ranged = matches!(name.as_str(), "scan" | "count");
+    if !ranged && (prefix.is_some() || from.is_some() || to.is_some()) {
+        return Err(Failure::Usage(format!("{name} doesn't take a range")));
+    }
+
+    let command = match name.as_str() {
+        "get" => match rest.pop() {
+            Some(key) => Command::Get(key),
+            None => return Err(Failure::Usage("get needs a key".to_string())),
+        },
+        "put" => {
+            let value = if rest.len() == 2 { rest.pop() } else { None };
+            Command::Put(rest.pop(), value)
+        }
+        "delete" => Command::Delete(rest.pop()),
+        "scan" => Command::Scan,
+        "count" => Command::Count,
+        "stats" => Command::Stats,
+        _ => return Err(Failure::Usage(format!("Unknown command {name}"))),
+    };
+
+    Ok(Args {
+        path: PathBuf::from(path),
+        command,
+        encoding,
+        prefix,
+        from,
+        to,
+    })
+}
+
+///
+/// Lines of stdin split at the first tab
+///
+fn read_entries(encoding: Encoding) -> Result<Vec<Entry>, Failure> {
+    let mut entries = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('\t')
+            .ok_or_else(|| Failure::Error(format!("Expected <key>\\t<value>, got {line:?}")))?;
+        entries.push((encoding.decode(key)?, encoding.decode(value)?));
+    }
+
+    Ok(entries)
+}
+
+fn read_keys(encoding: Encoding) -> Result<Vec<Vec<u8>>, Failure> {
+    let mut keys = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if !line.is_empty() {
+            keys.push(encoding.decode(&line)?);
+        }
+    }
+
+    Ok(keys)
+}
+
+///
+/// Whole of stdin as a single value, text encodings
+/// ignore the trailing newline
+///
+fn read_value(encoding: Encoding) -> Result<Vec<u8>, Failure> {
+    let mut value = Vec::new();
+    io::stdin().lock().read_to_end(&mut value)?;
+    if encoding == Encoding::Utf8 {
+        return Ok(value);
+    }
+
+    let text = String::from_utf8(value)?;
+    Ok(encoding.decode(text.trim_end())?)
+}
+
+///
+/// Range bounds intersected with the prefix, if any
+///
+fn bounds(args: &Args) -> Result<Range, Failure> {
+    let decode = |key: &Option<String>| -> Result<Option<Vec<u8>>, Failure> {
+        Ok(key
+            .as_deref()
+            .map(|key| args.encoding.decode(key))
+            .transpose()?)
+    };
+    let (prefix, from, to) = (
+        decode(&args.prefix)?,
+        decode(&args.from)?,
+        decode(&args.to)?,
+    );
+
+    let start = match (prefix, from) {
+        (Some(prefix), Some(from)) => Bound::Included(prefix.max(from)),
+        (Some(key), None) | (None, Some(key)) => Bound::Included(key),
+        (None, None) => Bound::Unbounded,
+    };
+    let end = match to {
+        Some(to) => Bound::Excluded(to),
+        None => Bound::Unbounded,
+    };
+
+    Ok((start, end))
+}
+
+fn scan<F>(connection: &mut Connection, args: &Args, mut f: F) -> Result<(), Failure>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), Failure>,
+{
+    let prefix = match &args.prefix {
+        Some(prefix) => args.encoding.decode(prefix)?,
+        None => Vec::new(),
+    };
+
+    for entry in connection.range(bounds(args)?)? {
+        let (key, value) = entry?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        f(&key, &value)?;
+    }
+
+    Ok(())
+}
+
+fn run(args: &Args) -> Result<(), Failure> {
+    let mut connection = Connection::open(&args.path)?;
+    let encoding = args.encoding;
+    let mut stdout = io::stdout().lock();
+
+    match &args.command {
+        Command::Get(key) => match connection.get(&encoding.decode(key)?)? {
+            Some(value) => {
+                stdout.write_all(&encoding.encode(&value))?;
+                stdout.write_all(b"\n")?;
+            }
+            None => return Err(Failure::NotFound),
+        },
+        Command::Put(Some(key), value) => {
+            let value = match value {
+                Some(value) => encoding.decode(value)?,
+                None => read_value(encoding)?,
+            };
+            connection.put(&encoding.decode(key)?, &value)?;
+        }
+        Command::Put(None, _) => {
+            let entries = read_entries(encoding)?;
+            let mut txn = connection.begin();
+            for (key, value) in &entries {
+                txn.put(key, value)?;
+            }
+            txn.commit()?;
+        }
+        Command::Delete(Some(key)) => {
+            if !connection.delete(&encoding.decode(key)?)? {
+                return Err(Failure::NotFound);
+            }
+        }
+        Command::Delete(None) => {
+            let keys = read_keys(encoding)?;
+            let mut txn = connection.begin();
+            for key in &keys {
+                txn.delete(key)?;
+            }
+            txn.commit()?;
+        }
+        Command::Scan => scan(&mut connection, args, |key, value| {
+            stdout.write_all(&encoding.encode(key))?;
+            stdout.write_all(b"\t")?;
+            stdout.write_all(&encoding.encode(value))?;
+            stdout.write_all(b"\n")?;
+            Ok(())
+        })?,
+        Command::Count => {
+            let mut count = 0u64;
+            scan(&mut connection, args, |_, _| {
+                count += 1;
+                Ok(())
+            })?;
+            writeln!(stdout, "{count}")?;
+        }
+        Command::Stats => {
+            let header = connection.header();
+            let report = connection.check()?;
+            writeln!(stdout, "format version  {}", header.version)?;
+            writeln!(stdout, "file size       {}", header.size)?;
+            writeln!(stdout, "tree height     {}", header.height)?;
+            writeln!(
+                stdout,
+                "tree pages      {}",
+                report.pages - header.free_pages as u64
+            )?;
+            writeln!(stdout, "free pages      {}", header.free_pages)?;
+            writeln!(stdout, "entries         {}", report.entries)?;
+            writeln!(stdout, "violations      {}", report.violations.len())?;
+        }
+    }
+
+    stdout.flush()?;
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let result = parse_args().and_then(|args| run(&args));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::NotFound) => {
+            eprintln!("tinystore: key not found");
+            ExitCode::from(1)
+        }
+        Err(Failure::Usage(err)) => {
+            if !err.is_empty() {
+                eprintln!("{err}\n");
+            }
+            eprintln!("{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(Failure::Error(err)) => {
+            eprintln!("tinystore: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
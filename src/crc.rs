@@ -0,0 +1,72 @@
+///
+/// CRC-32C (Castagnoli), reflected polynomial
+///
+const POLY: u32 = 0x82F63B78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+///
+/// Uses the SSE 4.2 crc32 instruction when
+/// available, a lookup table otherwise
+///
+pub(crate) fn crc32c(bytes: &[u8]) -> u32 {
+    crc32c_parts(&[bytes])
+}
+
+///
+/// Checksum of the given slices one after another
+///
+pub(crate) fn crc32c_parts(parts: &[&[u8]]) -> u32 {
+    !parts.iter().fold(!0, |crc, part| update(crc, part))
+}
+
+fn update(crc: u32, bytes: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("sse4.2") {
+        // SAFETY: the required cpu feature was just detected
+        return unsafe { update_sse42(crc, bytes) };
+    }
+
+    update_table(crc, bytes)
+}
+
+fn update_table(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn update_sse42(crc: u32, bytes: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut words = bytes.chunks_exact(8);
+    let mut crc = words.by_ref().fold(crc as u64, |crc, word| {
+        _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()))
+    }) as u32;
+
+    for &byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+
+    crc
+}
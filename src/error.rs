@@ -0,0 +1,67 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+///
+/// Everything that can go wrong talking to a database
+///
+#[derive(Debug)]
+pub enum Error {
+    /// No entry exists for the requested key
+    NotFound,
+    /// Reading or writing the database file failed
+    Io(io::Error),
+    /// Page contents don't describe a valid tree
+    Corrupt { page: u32 },
+    /// Key is longer than a page can split around
+    KeyTooLarge { len: usize, max: usize },
+    /// File doesn't start with the tinystore magic number
+    BadMagic { found: u32 },
+    /// File was written in a format this build can't read
+    UnsupportedVersion { found: u16, supported: u16 },
+    /// Write was rolled back along with a concurrent one that failed
+    Aborted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "Couldn't find entry with requested key"),
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::Corrupt { page } => write!(f, "Page {page} is corrupt"),
+            Error::KeyTooLarge { len, max } => {
+                write!(
+                    f,
+                    "Key of {len} bytes exceeds maximum key size of {max} bytes"
+                )
+            }
+            Error::BadMagic { found } => {
+                write!(f, "Not a tinystore database (magic number {found:#010x})")
+            }
+            Error::UnsupportedVersion { found, supported } => {
+                write!(
+                    f,
+                    "Database format version {found} isn't supported, \
+                     this build reads up to version {supported}"
+                )
+            }
+            Error::Aborted => write!(f, "Write rolled back after a concurrent write failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
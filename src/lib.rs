@@ -1 +1,8 @@
+mod crc;
+pub mod error;
 pub mod store;
+mod upgrade;
+pub mod vfs;
+mod wal;
+
+pub use error::{Error, Result};
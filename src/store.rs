@@ -1,12 +1,21 @@
-use anyhow::{anyhow, Result};
+use crate::crc::{crc32c, crc32c_parts};
+use crate::error::{Error, Result};
+use crate::upgrade;
+use crate::vfs::{OsVfs, Vfs, VfsFile};
+use crate::wal::Wal;
 use bincode::{config::BigEndian, Decode, Encode};
-use log::{debug, info};
-use std::fs::File;
-use std::io::{Seek, SeekFrom};
-use std::os::unix::fs::FileExt;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::{Duration, Instant};
 
-type PageId = u32;
+pub(crate) type PageId = u32;
 
 /// Index or 'id' of an item and its corresponding offset, page local
 ///
@@ -17,30 +26,173 @@ type Value = Vec<u8>;
 
 const BINCODE_CONFIG: bincode::config::Configuration<BigEndian> =
     bincode::config::standard().with_big_endian();
-const PAGE_SIZE: usize = 4096;
+pub(crate) const PAGE_SIZE: usize = 4096;
 ///
 /// Page Header:
 ///
 /// (1) # items
-/// (2) mgic numbers (different for first page)
+/// (2) next sibling page id (leaves only, 0 if none)
+/// (3) previous sibling page id (leaves only, 0 if none)
+/// (4) free bytes between offset array and items
+/// (5) crc32c of the rest of the page
 ///
-const PAGE_HEADER_SIZE: usize = 6;
-const METADATA_SIZE: usize = 18;
+const PAGE_HEADER_SIZE: usize = 16;
+const CHECKSUM_OFFSET: usize = 12;
+///
+/// Pages using less than this many bytes (items + offsets)
+/// are rebalanced with a sibling after a delete
+///
+const MIN_FILL: usize = (PAGE_SIZE - PAGE_HEADER_SIZE) / 4;
+///
+/// Leaf items larger than this have their value moved
+/// out to overflow pages, keeping only a short prefix
+///
+const MAX_ITEM_SIZE: usize = (PAGE_SIZE - PAGE_HEADER_SIZE) / 4;
+const OVERFLOW_PREFIX: usize = 32;
+const OVERFLOW_CHUNK: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+///
+/// Largest key accepted, leaves room for an overflowed
+/// value alongside it so items never exceed the max size
+///
+/// (lengths + tag + prefix + total len + overflow page id)
+///
+pub const MAX_KEY_SIZE: usize = MAX_ITEM_SIZE - (4 + 1 + OVERFLOW_PREFIX + 8 + 4);
+///
+/// Leaf values are tagged with how they are stored
+///
+const INLINE_VALUE: u8 = 0;
+const OVERFLOW_VALUE: u8 = 1;
+///
+/// Metadata slot at the start of page 0,
+/// followed by a crc32c of the slot
+///
+const METADATA_SIZE: usize = 32;
 const MAGIC: u32 = 0x54494E59;
-const CACHE_CAPACITY: u16 = 10;
+///
+/// Bumped whenever the on disk layout changes
+///
+/// (1) original layout, predates the version field: 6 byte page
+///     headers, values stored as is, no sibling links or free list
+/// (2) crc32c in every page header and after the metadata, sibling
+///     links, free list and tagged values that can spill into
+///     overflow pages
+///
+/// Layouts the tree went through on the way from 1 to 2 were
+/// never given a version of their own and can't be read
+///
+const FORMAT_VERSION: u16 = 2;
+///
+/// Default max # of pages held by the page cache
+///
+const CACHE_CAPACITY: usize = 256;
+///
+/// Write ahead log size past which it's
+/// checkpointed into the database file
+///
+const WAL_CHECKPOINT: u64 = 1024 * PAGE_SIZE as u64;
 
 #[derive(Encode, Decode, Debug)]
 struct MetaData {
+    magic: u32,
+    version: u16,
+    size: u64,
+    root: PageId,
+    height: u16,
+    free_head: PageId, // First page of the free list, 0 if empty
+    free_pages: u32,
+}
+
+///
+/// Metadata written before it had a version field
+///
+#[derive(Decode)]
+struct V1MetaData {
     magic: u32,
     size: u64,
     root: PageId,
     height: u16,
 }
 
+fn encode_metadata(meta: &MetaData) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; METADATA_SIZE + 4];
+    // Only fails if the header outgrows its slot, which
+    // isn't anything wrong with the file
+    bincode::encode_into_slice(meta, &mut buffer[..METADATA_SIZE], BINCODE_CONFIG)
+        .map_err(|err| Error::Io(io::Error::other(err)))?;
+    let crc = crc32c(&buffer[..METADATA_SIZE]);
+    buffer[METADATA_SIZE..].copy_from_slice(&crc.to_be_bytes());
+
+    Ok(buffer)
+}
+
+///
+/// Checks the magic number and version before
+/// trusting anything else in the header, version
+/// 1 files have no checksum to check
+///
+fn decode_metadata(buffer: &[u8]) -> Result<MetaData> {
+    let corrupt = |_| Error::Corrupt { page: 0 };
+    let read = match bincode::decode_from_slice::<u32, _>(buffer, BINCODE_CONFIG) {
+        Ok((MAGIC, read)) => read,
+        Ok((found, _)) => return Err(Error::BadMagic { found }),
+        Err(_) => {
+            let found = u32::from_be_bytes(buffer[..4].try_into().unwrap());
+            return Err(Error::BadMagic { found });
+        }
+    };
+
+    // Version 1 has the size right after the magic number, always
+    // a page or more so varint encoded behind a multi byte tag,
+    // while versions fit in the single byte form
+    if buffer[read] >= 251 {
+        let meta: V1MetaData = bincode::decode_from_slice(buffer, BINCODE_CONFIG)
+            .map_err(corrupt)?
+            .0;
+
+        return Ok(MetaData {
+            magic: meta.magic,
+            version: 1,
+            size: meta.size,
+            root: meta.root,
+            height: meta.height,
+            free_head: 0,
+            free_pages: 0,
+        });
+    }
+
+    let meta: MetaData = bincode::decode_from_slice(buffer, BINCODE_CONFIG)
+        .map_err(corrupt)?
+        .0;
+    if meta.version == 0 || meta.version > FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion {
+            found: meta.version,
+            supported: FORMAT_VERSION,
+        });
+    }
+
+    let crc = u32::from_be_bytes(buffer[METADATA_SIZE..METADATA_SIZE + 4].try_into().unwrap());
+    if crc != crc32c(&buffer[..METADATA_SIZE]) {
+        return Err(Error::Corrupt { page: 0 });
+    }
+
+    Ok(meta)
+}
+
+fn read_metadata(file: &dyn VfsFile) -> Result<MetaData> {
+    // Files shorter than the header are padded
+    // with zeros and fail the magic number check
+    let mut buffer = vec![0u8; METADATA_SIZE + 4];
+    let len = file.size()?.min(buffer.len() as u64) as usize;
+    file.read_exact_at(&mut buffer[..len], 0)?;
+
+    decode_metadata(&buffer)
+}
+
 ///
 /// Could also be called node, abstraction
 /// for page level operations
 ///
+#[derive(Clone)]
 struct PageData {
     buf: [u8; PAGE_SIZE],
 }
@@ -53,23 +205,32 @@ struct PageData {
 ///
 ///  Where value is either:
 ///
-///  (1) arbitrary size byte array
+///  (1) tagged byte array (leaves)
 ///  (2) child page id / ptr
 ///
+///  Large leaf values are stored as:
+///
+///   ---------------------------------------------------------
+///  | tag | prefix | total len (u64) | first overflow page id |
+///  ---------------------------------------------------------
+///
 ///
 /// *Use empty key for the n+1th internal node child ptr
-
+///
 /// In internal nodes store the leftmost child pointer
 /// without a key that way you never have to handle half items
 /// because that node will always split to the right
-
 impl PageData {
     fn new() -> PageData {
-        PageData {
+        let mut page = PageData {
             buf: [0u8; PAGE_SIZE],
-        }
+        };
+        page.set_free(PAGE_SIZE - PAGE_HEADER_SIZE);
+
+        page
     }
 
+    #[allow(dead_code)]
     pub fn print_items(&self) {
         for i in 0..self.get_n_items() {
             let (key, value) = self.get_item(i);
@@ -77,34 +238,46 @@ impl PageData {
         }
     }
 
-    // pub fn search(&self, target: &Key) -> Option<(Key, Value)> {
-    //     for i in 0..self.get_n_items() {
-    //         let (key, value) = self.get_item(i);
-    //         if key == *target {
-    //             return Some((key, value));
-    //         }
-    //     }
-    //     None
-    // }
-
-    pub fn lin_find_place(&self, key: &Key) -> ItemPtr {
-        let n = self.get_n_items();
-        &for i in 0..n {
-            if !self.gt_entry(key, i) {
-                return i;
-            }
-        };
+    ///
+    /// Binary searches a leaf for the given key, returning
+    /// Ok with its item pointer if found, or Err with the
+    /// pointer it would be inserted at otherwise
+    ///
+    pub fn search(&self, key: &[u8]) -> std::result::Result<ItemPtr, ItemPtr> {
+        let ip = self.lower_bound(key, self.get_n_items());
+        if ip < self.get_n_items() && self.key_at(ip) == key {
+            Ok(ip)
+        } else {
+            Err(ip)
+        }
+    }
 
-        n
+    ///
+    /// Returns pointer to the internal node item whose
+    /// child subtree would hold the given key
+    ///
+    pub fn find_child(&self, key: &[u8]) -> ItemPtr {
+        // Keyless rightmost child pointer isn't searched,
+        // it catches everything past the last key
+        self.lower_bound(key, self.get_n_items() - 1)
     }
+
     ///
-    /// Evaluates a greater than comparison between 2 items,
-    /// returning true if the left is greater
+    /// Returns pointer to the first of the leading n
+    /// items with a key not less than the given key
     ///
-    pub fn gt_entry(&self, lkey: &Key, ip: ItemPtr) -> bool {
-        let (rkey, _) = self.get_item(ip);
+    fn lower_bound(&self, key: &[u8], n: usize) -> ItemPtr {
+        let (mut lo, mut hi) = (0, n);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.key_at(mid) < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
 
-        *lkey > rkey
+        lo
     }
 
     pub fn as_slice(&self) -> &[u8] {
@@ -115,8 +288,12 @@ impl PageData {
         self.buf.as_mut_slice()
     }
 
-    pub fn copy_into(&mut self, src: &mut [u8]) {
-        self.buf.copy_from_slice(src);
+    ///
+    /// Whether any item fits without a split, leaf values
+    /// are stored so items never exceed the max size
+    ///
+    fn has_room(&self) -> bool {
+        self.get_free() > MAX_ITEM_SIZE + 2
     }
 
     pub fn append_item(&mut self, key: &Key, value: &Value) -> bool {
@@ -125,12 +302,12 @@ impl PageData {
 
     pub fn insert_item(&mut self, ip: ItemPtr, key: &Key, value: &Value) -> bool {
         let n_items = self.get_n_items();
-        let size = self.get_size();
+        let free = self.get_free();
         let kl = key.len();
         let vl = value.len();
         let il = 4 + kl + vl;
 
-        if PAGE_SIZE - PAGE_HEADER_SIZE > (n_items * 2) + size + il + 2 {
+        if free > il + 2 {
             // Shift greater items towards header,
             // offsets will decrease
             for i in (ip..n_items).rev() {
@@ -145,7 +322,7 @@ impl PageData {
 
             if ip != n_items {
                 let start = self.get_offs(n_items);
-                self.buf.copy_within(start..offs + il, start - il);
+                self.buf.copy_within(start + il..offs + il, start);
             }
 
             self.set_offs(ip, offs);
@@ -157,6 +334,7 @@ impl PageData {
             self.buf[offs + kl..offs + kl + vl].copy_from_slice(value.as_slice());
 
             self.set_n_items(n_items + 1);
+            self.set_free(free - il - 2);
 
             true
         } else {
@@ -165,31 +343,40 @@ impl PageData {
     }
 
     ///
-    /// Splits entries into two halves based on the ceil split point,
-    /// moves entries greater than split point to new page and returns
-    /// the pointer to the split entry. Split entry remains in source
-    /// page.
+    /// Splits entries, along with a new item that didn't fit
+    /// at the given pointer, into two halves of roughly equal
+    /// size. Moves entries greater than split point to new page
+    /// and returns it. Split entry remains in source page.
     ///
-    pub fn split(&mut self) -> (ItemPtr, PageData) {
+    pub fn split(&mut self, ip: ItemPtr, key: &Key, value: &Value) -> PageData {
+        let mut items = self.get_items();
+        items.insert(ip, (key.clone(), value.clone()));
+        let right_items = items.split_off(split_point(&items));
+
         let mut right = PageData::new();
-        let n_items = self.get_n_items();
-        let sp = ((n_items + 1) / 2) - 1;
+        self.set_items(&items);
+        right.set_items(&right_items);
 
-        for i in (sp..n_items).rev() {
-            let (key, value) = self.remove_item(i);
-            right.insert_item(0, &key, &value);
-        }
+        right
+    }
 
-        (sp - 1, right)
+    ///
+    /// Replaces every item while keeping the
+    /// rest of the page header intact
+    ///
+    fn set_items(&mut self, items: &[(Key, Value)]) {
+        self.clear_items();
+        for (key, value) in items {
+            self.append_item(key, value);
+        }
     }
 
-    pub fn remove_item(&mut self, ip: ItemPtr) -> (Key, Value) {
+    pub fn remove_item(&mut self, ip: ItemPtr) {
         let n_items = self.get_n_items();
         let ioffs = self.get_offs(ip);
         let start = self.get_offs(n_items - 1);
         let offs = PAGE_HEADER_SIZE + (2 * ip);
-        let (key, value) = self.get_item(ip);
-        let il = key.len() + value.len() + 4;
+        let il = self.key_at(ip).len() + self.value_at(ip).len() + 4;
 
         // Update other items offsets
         for i in ip..n_items {
@@ -197,7 +384,7 @@ impl PageData {
             self.set_offs(i, self.get_offs(i) + il);
         }
 
-        let free_start = PAGE_HEADER_SIZE + ((n_items + 1) * 2);
+        let free_start = PAGE_HEADER_SIZE + (n_items * 2);
 
         // Shift offsets left
         self.buf.copy_within(offs + 2..free_start, offs);
@@ -206,40 +393,144 @@ impl PageData {
         self.buf.copy_within(start..ioffs, start + il);
 
         self.set_n_items(n_items - 1);
+        self.set_free(self.get_free() + il + 2);
+    }
+
+    ///
+    /// Copies out every item in the page, in order
+    ///
+    pub fn get_items(&self) -> Vec<(Key, Value)> {
+        (0..self.get_n_items()).map(|i| self.get_item(i)).collect()
+    }
+
+    ///
+    /// Borrows the key of an item in place
+    ///
+    pub fn key_at(&self, ip: ItemPtr) -> &[u8] {
+        let offs = self.get_offs(ip);
+        let kl = self.get_u16(offs) as usize;
 
-        (key, value)
+        &self.buf[offs + 4..offs + 4 + kl]
     }
 
-    pub fn get_item(&self, ip: ItemPtr) -> (Key, Value) {
+    ///
+    /// Borrows the value of an item in place
+    ///
+    pub fn value_at(&self, ip: ItemPtr) -> &[u8] {
         let offs = self.get_offs(ip);
         let kl = self.get_u16(offs) as usize;
         let vl = self.get_u16(offs + 2) as usize;
 
-        let offs = offs + 4;
-        (
-            self.buf[offs..offs + kl].to_vec(),
-            self.buf[offs + kl..offs + kl + vl].to_vec(),
-        )
+        &self.buf[offs + 4 + kl..offs + 4 + kl + vl]
+    }
+
+    pub fn get_item(&self, ip: ItemPtr) -> (Key, Value) {
+        (self.key_at(ip).to_vec(), self.value_at(ip).to_vec())
+    }
+
+    ///
+    /// Returns number of bytes taken up by items
+    /// and their offset entries
+    ///
+    fn get_used(&self) -> usize {
+        PAGE_SIZE - PAGE_HEADER_SIZE - self.get_free()
+    }
+
+    fn get_child(&self, ip: ItemPtr) -> PageId {
+        u32::from_be_bytes(self.value_at(ip).try_into().unwrap())
     }
 
     ///
-    /// Returns total byte size of every item in the page,
-    /// not including 2 byte offset entry
+    /// Drops every item while keeping the rest
+    /// of the page header intact
+    ///
+    fn clear_items(&mut self) {
+        self.set_n_items(0);
+        self.set_free(PAGE_SIZE - PAGE_HEADER_SIZE);
+    }
+
+    fn set_free(&mut self, free: usize) {
+        self.set_u16(10, free as u16)
+    }
+
+    fn get_free(&self) -> usize {
+        self.get_u16(10) as usize
+    }
+
     ///
+    /// Returns the first slot whose item doesn't fit between
+    /// the offset array and the end of the page, if any
     ///
-    fn get_size(&self) -> usize {
-        let mut size: usize = 0;
-        for i in 0..self.get_n_items() {
-            let (key, value) = self.get_item(i);
-            size += key.len() + value.len() + 4;
+    fn find_bad_slot(&self) -> Option<ItemPtr> {
+        let n_items = self.get_n_items();
+        let items_start = PAGE_HEADER_SIZE + n_items * 2;
+        if items_start > PAGE_SIZE {
+            return Some(0);
         }
 
-        size
+        (0..n_items).find(|&ip| {
+            let offs = self.get_offs(ip);
+            offs < items_start
+                || offs + 4 > PAGE_SIZE
+                || offs + 4 + self.get_u16(offs) as usize + self.get_u16(offs + 2) as usize
+                    > PAGE_SIZE
+        })
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        crc32c_parts(&[
+            &self.buf[..CHECKSUM_OFFSET],
+            &self.buf[CHECKSUM_OFFSET + 4..],
+        ])
+    }
+
+    ///
+    /// Stamps the page with a checksum of its
+    /// contents, done before it can reach disk
+    ///
+    fn update_checksum(&mut self) {
+        let crc = self.compute_checksum();
+        self.set_u32(CHECKSUM_OFFSET, crc);
+    }
+
+    fn verify_checksum(&self) -> bool {
+        self.get_u32(CHECKSUM_OFFSET) == self.compute_checksum()
+    }
+
+    ///
+    /// Overflow pages hold a single chunk of a large value
+    /// after the header, with its length in place of the
+    /// item count and the next page of the chain as sibling
+    ///
+    fn get_chunk(&self) -> &[u8] {
+        &self.buf[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + self.get_n_items()]
+    }
+
+    fn set_chunk(&mut self, chunk: &[u8]) {
+        self.set_n_items(chunk.len());
+        self.buf[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+    }
+
+    fn set_child(&mut self, ip: ItemPtr, pid: PageId) {
+        let offs = self.get_offs(ip);
+        let kl = self.get_u16(offs) as usize;
+        self.set_u32(offs + 4 + kl, pid);
+    }
+
+    fn set_next(&mut self, pid: PageId) {
+        self.set_u32(2, pid)
+    }
+
+    fn get_next(&self) -> PageId {
+        self.get_u32(2)
     }
 
-    fn get_child(&self, ip: ItemPtr) -> u32 {
-        let (key, value) = self.get_item(ip);
-        u32::from_be_bytes(value.try_into().unwrap())
+    fn set_prev(&mut self, pid: PageId) {
+        self.set_u32(6, pid)
+    }
+
+    fn get_prev(&self) -> PageId {
+        self.get_u32(6)
     }
 
     fn set_n_items(&mut self, data: usize) {
@@ -278,28 +569,104 @@ impl PageData {
     }
 }
 
+///
+/// Space taken by an item, including its offset entry
+///
+fn item_size((key, value): &(Key, Value)) -> usize {
+    key.len() + value.len() + 6
+}
+
+///
+/// Returns true if the given items would fit
+/// together on a single page
+///
+fn items_fit(items: &[(Key, Value)]) -> bool {
+    let used: usize = items.iter().map(item_size).sum();
+
+    PAGE_SIZE - PAGE_HEADER_SIZE > used
+}
+
+///
+/// Lays items out in order over as few pages as they fit in
+///
+fn fill_pages(items: Vec<(Key, Value)>) -> Vec<PageData> {
+    let mut pages = vec![PageData::new()];
+    for (key, value) in items {
+        if !pages.last_mut().unwrap().append_item(&key, &value) {
+            let mut page = PageData::new();
+            page.append_item(&key, &value);
+            pages.push(page);
+        }
+    }
+
+    pages
+}
+
+///
+/// Index splitting items into two halves of roughly
+/// equal size, neither of which is left empty
+///
+fn split_point(items: &[(Key, Value)]) -> usize {
+    let total: usize = items.iter().map(item_size).sum();
+    let mut mid = 0;
+    let mut acc = 0;
+    while mid < items.len() - 1 && acc < total / 2 {
+        acc += item_size(&items[mid]);
+        mid += 1;
+    }
+
+    mid.max(1)
+}
+
+///
+/// Root pointer and height of the tree, atomic so inserts
+/// can run side by side, only changed by one holding the
+/// write latch on the metadata page
+///
 struct BTree {
-    root: PageId,
-    pub height: u16,
+    root: AtomicU32,
+    height: AtomicU16,
 }
 
 impl BTree {
-    pub fn initialize(meta: &MetaData) -> BTree {
+    fn new(root: PageId, height: u16) -> BTree {
         BTree {
-            root: meta.root,
-            height: meta.height,
+            root: AtomicU32::new(root),
+            height: AtomicU16::new(height),
         }
     }
 
-    fn create_root(&mut self, io: &mut PageCache, overflow: (Key, PageData)) -> Result<()> {
-        let (sk, right) = overflow;
-        let pid = self.root;
+    pub fn initialize(meta: &MetaData) -> BTree {
+        BTree::new(meta.root, meta.height)
+    }
+
+    // Latches order every access, the atomics only have to
+    // make changing the root through a shared tree possible
+    fn root(&self) -> PageId {
+        self.root.load(Ordering::Relaxed)
+    }
+
+    fn height(&self) -> u16 {
+        self.height.load(Ordering::Relaxed)
+    }
+
+    fn set_root(&self, root: PageId, height: u16) {
+        self.root.store(root, Ordering::Relaxed);
+        self.height.store(height, Ordering::Relaxed);
+    }
+
+    fn create_root(&self, io: &PageCache, overflow: (Key, PageData)) -> Result<()> {
+        let (sk, mut right) = overflow;
+        let (pid, height) = (self.root(), self.height());
 
-        let page = io.get_page(pid)?;
         let right_id = io.new_page()?;
         let root_id = io.new_page()?;
         info!("Creating root at page id {root_id}");
 
+        if height == 1 {
+            self.link_leaf(io, pid, right_id, &mut right)?;
+        }
+
         let mut root = PageData::new();
         root.insert_item(0, &sk, &pid.to_be_bytes().to_vec());
         root.insert_item(1, &vec![0u8; 0], &right_id.to_be_bytes().to_vec());
@@ -307,55 +674,233 @@ impl BTree {
         io.commit_page(right_id, &right)?;
         io.commit_page(root_id, &root)?;
 
-        self.root = root_id;
-        self.height += 1;
+        self.set_root(root_id, height + 1);
 
         Ok(())
     }
 
-    pub fn btree_get(&self, io: &mut PageCache, key: &Key) -> Result<Value> {
-        let pid = self.find_leaf(io, self.root, key, self.height - 1)?;
-        let page = io.get_page(pid)?;
+    pub fn btree_get(&self, io: &PageCache, key: &Key) -> Result<Option<Value>> {
+        let (root, height) = (self.root(), self.height());
+        if root != 0 {
+            let pid = self.find_leaf(io, root, key, height - 1)?;
+            let page = io.get_page(pid)?;
 
-        // TODO: binary search within pages
-        for i in 0..page.get_n_items() {
-            let (k, v) = page.get_item(i);
-            if k == *key {
-                return Ok(v);
+            if let Ok(ip) = page.search(key) {
+                return self.load_value(io, pid, page.value_at(ip)).map(Some);
             }
         }
 
-        Err(anyhow!("Couldn't find entry with requested key"))
+        Ok(None)
     }
 
-    fn find_leaf(
-        &self,
-        io: &mut PageCache,
-        mut pid: PageId,
-        key: &Key,
-        height: u16,
-    ) -> Result<PageId> {
+    ///
+    /// Encodes a value for storage in a leaf, moving all but
+    /// a short prefix of it out to a chain of overflow pages
+    /// when the item would be too large to split around
+    ///
+    fn store_value(&self, io: &PageCache, key: &Key, value: &Value) -> Result<Value> {
+        if 4 + key.len() + 1 + value.len() <= MAX_ITEM_SIZE {
+            let mut stored = vec![INLINE_VALUE];
+            stored.extend_from_slice(value);
+            return Ok(stored);
+        }
+
+        let (prefix, rest) = value.split_at(OVERFLOW_PREFIX.min(value.len()));
+        let chunks: Vec<&[u8]> = rest.chunks(OVERFLOW_CHUNK).collect();
+        let pids = chunks
+            .iter()
+            .map(|_| io.new_page())
+            .collect::<Result<Vec<PageId>>>()?;
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut page = PageData::new();
+            page.set_chunk(chunk);
+            page.set_next(pids.get(i + 1).copied().unwrap_or(0));
+            io.commit_page(pids[i], &page)?;
+        }
+
+        let mut stored = vec![OVERFLOW_VALUE];
+        stored.extend_from_slice(prefix);
+        stored.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        stored.extend_from_slice(&pids.first().copied().unwrap_or(0).to_be_bytes());
+
+        Ok(stored)
+    }
+
+    ///
+    /// Frees the overflow chain of a value
+    /// being replaced or deleted, if it has one
+    ///
+    fn free_value(&self, io: &PageCache, stored: &[u8]) -> Result<()> {
+        let mut pid = overflow_head(stored);
+        while pid != 0 {
+            let next = io.get_chain_page(pid)?.get_next();
+            io.free_page(pid)?;
+            pid = next;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Decodes a value stored in the given leaf,
+    /// reassembling it from overflow pages if needed
+    ///
+    fn load_value(&self, io: &PageCache, leaf: PageId, stored: &[u8]) -> Result<Value> {
+        match stored.split_first() {
+            Some((&INLINE_VALUE, value)) => Ok(value.to_vec()),
+            Some((&OVERFLOW_VALUE, rest)) if rest.len() >= 12 => {
+                let (prefix, tail) = rest.split_at(rest.len() - 12);
+                let len = u64::from_be_bytes(tail[..8].try_into().unwrap()) as usize;
+                let mut pid = PageId::from_be_bytes(tail[8..].try_into().unwrap());
+
+                let mut value = Vec::with_capacity(len);
+                let mut page_id = leaf;
+                value.extend_from_slice(prefix);
+                while value.len() < len && pid != 0 {
+                    let page = io.get_chain_page(pid)?;
+                    value.extend_from_slice(page.get_chunk());
+                    page_id = pid;
+                    pid = page.get_next();
+                }
+
+                // Chain ended early or ran long
+                if value.len() != len {
+                    return Err(Error::Corrupt { page: page_id });
+                }
+
+                Ok(value)
+            }
+            _ => Err(Error::Corrupt { page: leaf }),
+        }
+    }
+
+    ///
+    /// Positions a cursor on the first entry
+    /// inside the given key range
+    ///
+    pub fn btree_range<'a, R: RangeBounds<Key>>(
+        &'a self,
+        io: &'a PageCache,
+        range: R,
+    ) -> Result<Cursor<'a>> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        let mut cursor = Cursor {
+            tree: self,
+            io,
+            low: start.clone(),
+            high: end.clone(),
+            start,
+            end,
+            front: None,
+            back: None,
+            done: self.root() == 0,
+        };
+
+        if !cursor.done {
+            cursor.front = Some(cursor.seek_start()?);
+        }
+
+        Ok(cursor)
+    }
+
+    fn find_leaf(&self, io: &PageCache, mut pid: PageId, key: &Key, height: u16) -> Result<PageId> {
         if height == 0 {
             Ok(pid)
         } else {
             let page = io.get_page(pid)?;
-            let ip = page.lin_find_place(key);
-            pid = page.get_child(ip.min(page.get_n_items() - 1));
+            pid = page.get_child(page.find_child(key));
             self.find_leaf(io, pid, key, height - 1)
         }
     }
 
-    pub fn btree_insert(&mut self, io: &mut PageCache, key: &Key, value: &Value) -> Result<()> {
-        if self.root == 0 {
-            let mut root = PageData::new();
-            root.insert_item(0, key, value);
-            io.commit_page(1, &root);
-            self.root = 1;
-            self.height = 1;
+    fn find_last_leaf(&self, io: &PageCache, pid: PageId, height: u16) -> Result<PageId> {
+        if height == 0 {
+            Ok(pid)
+        } else {
+            let page = io.get_page(pid)?;
+            let pid = page.get_child(page.get_n_items() - 1);
+            self.find_last_leaf(io, pid, height - 1)
+        }
+    }
+
+    ///
+    /// Inserts or replaces an entry, safe to run
+    /// alongside other inserts into the same tree
+    ///
+    /// Most inserts only have to write latch their leaf,
+    /// the rest go down again latching every page a
+    /// split could reach
+    ///
+    pub fn btree_insert(&self, io: &PageCache, key: &Key, value: &Value) -> Result<()> {
+        let value = &self.store_value(io, key, value)?;
+
+        if !self.insert_in_place(io, key, value)? {
+            self.insert_splitting(io, key, value)?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Read latches its way down to the leaf, coupling each
+    /// latch with the next, fails if the leaf is full
+    ///
+    fn insert_in_place(&self, io: &PageCache, key: &Key, value: &Value) -> Result<bool> {
+        let meta = io.latches.read(0);
+        let (mut pid, height) = (self.root(), self.height());
+        if pid == 0 {
+            return Ok(false);
+        }
+
+        let mut _latch = io.latches.acquire(pid, height == 1);
+        drop(meta);
+        for level in (1..height).rev() {
+            let page = io.get_page(pid)?;
+            pid = page.get_child(page.find_child(key));
+            // Parent is let go only once the child is held
+            _latch = io.latches.acquire(pid, level == 1);
+        }
+
+        let mut page = io.get_page(pid)?;
+        let (ip, replaced) = match page.search(key) {
+            Ok(ip) => (ip, Some(page.value_at(ip).to_vec())),
+            Err(ip) => (ip, None),
+        };
+        if self.try_insert(&mut page, ip, key, value, 0).is_some() {
+            return Ok(false);
+        }
+
+        if let Some(stored) = replaced {
+            self.free_value(io, &stored)?;
+        }
+        io.commit_page(pid, &page)?;
+
+        Ok(true)
+    }
+
+    ///
+    /// Write latches every page on the way down, letting go
+    /// of those above a page with room for another item as
+    /// no split can reach past it
+    ///
+    fn insert_splitting(&self, io: &PageCache, key: &Key, value: &Value) -> Result<()> {
+        let mut held = vec![io.latches.write(0)];
+        let (root, height) = (self.root(), self.height());
+
+        if root == 0 {
+            let mut page = PageData::new();
+            page.insert_item(0, key, value);
+            let pid = io.new_page()?;
+            io.commit_page(pid, &page)?;
+            self.set_root(pid, 1);
             return Ok(());
         }
 
-        if let Some(root_overflow) = self.insert(io, self.root, key, value, self.height - 1)? {
+        held.push(io.latches.write(root));
+        if let Some(root_overflow) = self.insert(io, root, key, value, height - 1, &mut held)? {
             self.create_root(io, root_overflow)?;
         }
 
@@ -363,275 +908,2865 @@ impl BTree {
     }
 
     fn balance(
-        &mut self,
-        io: &mut PageCache,
+        &self,
+        io: &PageCache,
         ip: ItemPtr,
-        sk: Key,
+        overflow: (Key, PageData),
         parent: &mut PageData,
-        child: &mut PageData,
-        mut right: PageData,
-        key: &Key,
-        value: &Value,
         pid: PageId,
-        cid: PageId,
         height: u16,
     ) -> Result<Option<(Key, PageData)>> {
+        let (sk, mut right) = overflow;
+        let cid = parent.get_child(ip);
         let rid = io.new_page()?;
-        io.commit_page(rid, &right);
+        if height == 1 {
+            self.link_leaf(io, cid, rid, &mut right)?;
+        }
+        io.commit_page(rid, &right)?;
 
-        // Swap keys with child pointers
+        // Right half keeps the old upper bound, left half
+        // is inserted in front with the split key
         //
-        //  |....| .... | left child ptr | Key 1 | ....
-        //  |....| .... | left child ptr | Key 2 | Right child ptr | Key 1 | ....
+        //  |....| .... | child ptr | Key 1 | ....
+        //  |....| .... | left child ptr | Key 2 | right child ptr | Key 1 | ....
         //
-        let (k1, _) = parent.remove_item(ip);
+        // Swapping the child pointer in place means only one
+        // item is added, so only one split can happen here
+        parent.set_child(ip, rid);
 
-        // Can this overflow?
-        parent.insert_item(ip, &sk, &cid.to_be_bytes().to_vec());
+        let overflow = self.try_insert(parent, ip, &sk, &cid.to_be_bytes().to_vec(), height);
 
-        let overflow = self.try_insert(
-            io,
-            parent,
-            pid,
-            ip + 1,
-            &k1,
-            &rid.to_be_bytes().to_vec(),
-            height,
-        );
-
-        io.commit_page(pid, &parent)?;
+        io.commit_page(pid, parent)?;
 
         Ok(overflow)
     }
 
-    fn insert(
-        &mut self,
-        io: &mut PageCache,
+    ///
+    /// The page is write latched, as the last of the held latches
+    ///
+    fn insert<'a>(
+        &self,
+        io: &'a PageCache,
         pid: PageId,
         key: &Key,
         value: &Value,
         height: u16,
+        held: &mut Vec<Latch<'a>>,
     ) -> Result<Option<(Key, PageData)>> {
-        let mut page = io.get_page(pid)?;
-        let n = page.get_n_items();
-        let ip = page.lin_find_place(key);
+        let page = io.get_page(pid)?;
+        if page.has_room() {
+            held.drain(..held.len() - 1);
+        }
 
         if height == 0 {
-            let overflow = self.try_insert(io, &mut page, pid, ip, key, value, height);
-            io.commit_page(pid, &page)?;
-            return Ok(overflow);
+            return self.insert_leaf(io, pid, key, value);
         }
 
-        let child = page.get_child(ip.min(n - 1)) as PageId;
-        let mut child_page = io.get_page(child)?;
-
-        let overflow = if height == 1 {
-            let cip = child_page.lin_find_place(key);
-            let over = self.try_insert(io, &mut child_page, child, cip, key, value, height - 1);
+        let ci = page.find_child(key);
+        let child = page.get_child(ci);
 
-            // Only save the newest version
-            io.commit_page(child, &child_page);
+        held.push(io.latches.write(child));
+        let overflow = self.insert(io, child, key, value, height - 1, held)?;
 
-            over
+        let mut page = io.get_page(pid)?;
+        if let Some(overflow) = overflow {
+            self.balance(io, ci, overflow, &mut page, pid, height)
         } else {
-            self.insert(io, child, key, value, height - 1)?
+            Ok(None)
+        }
+    }
+
+    fn insert_leaf(
+        &self,
+        io: &PageCache,
+        pid: PageId,
+        key: &Key,
+        value: &Value,
+    ) -> Result<Option<(Key, PageData)>> {
+        let mut page = io.get_page(pid)?;
+        let ip = match page.search(key) {
+            Ok(ip) => {
+                self.free_value(io, page.value_at(ip))?;
+                ip
+            }
+            Err(ip) => ip,
         };
 
-        page = io.get_page(pid)?;
-        if let Some((sk, right)) = overflow {
-            self.balance(
-                io,
-                ip.min(n - 1),
-                sk,
-                &mut page,
-                &mut child_page,
-                right,
-                key,
-                value,
-                pid,
-                child,
-                height,
-            )
-        } else {
-            Ok(None)
+        let overflow = self.try_insert(&mut page, ip, key, value, 0);
+        io.commit_page(pid, &page)?;
+
+        Ok(overflow)
+    }
+
+    ///
+    /// Splices a freshly split off right leaf
+    /// into the sibling list after its left half
+    ///
+    fn link_leaf(
+        &self,
+        io: &PageCache,
+        lid: PageId,
+        rid: PageId,
+        right: &mut PageData,
+    ) -> Result<()> {
+        let mut left = io.get_page(lid)?;
+        let next = left.get_next();
+
+        right.set_prev(lid);
+        right.set_next(next);
+        left.set_next(rid);
+        io.commit_page(lid, &left)?;
+
+        if next != 0 {
+            // Latches are only ever taken left to right
+            // along leaves, so this can't deadlock
+            let _latch = io.latches.write(next);
+            let mut page = io.get_page(next)?;
+            page.set_prev(rid);
+            io.commit_page(next, &page)?;
         }
+
+        Ok(())
     }
 
     pub fn try_insert(
-        &mut self,
-        io: &mut PageCache,
+        &self,
         page: &mut PageData,
-        pid: PageId,
         ip: ItemPtr,
         key: &Key,
         value: &Value,
         height: u16,
     ) -> Option<(Key, PageData)> {
+        // Overwrite existing entry instead of storing a duplicate
+        if height == 0 && ip < page.get_n_items() && page.key_at(ip) == key.as_slice() {
+            page.remove_item(ip);
+        }
+
+        if page.insert_item(ip, key, value) {
+            return None;
+        }
+
         // Return split key and right node page data
-        let okay = page.insert_item(ip, key, value);
-        io.commit_page(pid, &page);
-        if !okay {
-            let (mut sp, mut right) = page.split();
-            let (sk, sv) = if ip == sp + 1 {
-                sp += 1;
-                (key.clone(), value.clone())
-            } else {
-                page.remove_item(sp)
-            };
+        let right = page.split(ip, key, value);
+        let sp = page.get_n_items() - 1;
+        let (sk, sv) = page.get_item(sp);
+
+        if height >= 1 {
+            // Split key moves up, its child becomes
+            // the keyless rightmost pointer
+            page.remove_item(sp);
+            page.append_item(&Vec::new(), &sv);
+        }
 
-            if height >= 1 {
-                page.insert_item(sp, &vec![0u8; 0], &sv);
-            } else {
-                page.insert_item(sp, &sk, &sv);
+        Some((sk, right))
+    }
+
+    ///
+    /// Rewrites the tree into new pages filled as far as
+    /// they go, overflow chains are kept as they are
+    ///
+    /// Old pages are left unreachable rather than freed,
+    /// vacuuming drops them along with the free list
+    ///
+    fn pack(&self, io: &PageCache) -> Result<()> {
+        let (root, height) = (self.root(), self.height());
+        if root == 0 {
+            return Ok(());
+        }
+
+        // Leaves in key order, found level by level
+        let mut level = vec![root];
+        for _ in 1..height {
+            let mut children = Vec::new();
+            for pid in level {
+                let page = io.get_page(pid)?;
+                children.extend((0..page.get_n_items()).map(|ip| page.get_child(ip)));
             }
+            level = children;
+        }
+
+        let mut items = Vec::new();
+        for pid in level {
+            items.extend(io.get_page(pid)?.get_items());
+        }
+        if items.is_empty() {
+            self.set_root(0, 0);
+            return Ok(());
+        }
+
+        let mut leaves = fill_pages(items);
+        let pids = leaves
+            .iter()
+            .map(|_| io.new_page())
+            .collect::<Result<Vec<PageId>>>()?;
+        let mut nodes = Vec::new();
+        for (i, leaf) in leaves.iter_mut().enumerate() {
+            leaf.set_prev(if i == 0 { 0 } else { pids[i - 1] });
+            leaf.set_next(pids.get(i + 1).copied().unwrap_or(0));
+            io.commit_page(pids[i], leaf)?;
+
+            let last = leaf.key_at(leaf.get_n_items() - 1).to_vec();
+            nodes.push((last, pids[i].to_be_bytes().to_vec()));
+        }
 
-            if *key > sk {
-                let ip = right.lin_find_place(key);
-                right.insert_item(ip, &key, &value);
-            } else if *key < sk {
-                let ip = page.lin_find_place(key);
-                page.insert_item(ip, &key, &value);
+        let mut height = 1;
+        while nodes.len() > 1 {
+            let mut parents = Vec::new();
+            for mut page in fill_pages(nodes) {
+                // Last key moves up, its child becomes
+                // the keyless rightmost pointer
+                let (key, child) = page.get_item(page.get_n_items() - 1);
+                page.remove_item(page.get_n_items() - 1);
+                page.append_item(&Vec::new(), &child);
+
+                let pid = io.new_page()?;
+                io.commit_page(pid, &page)?;
+                parents.push((key, pid.to_be_bytes().to_vec()));
             }
 
-            return Some((sk, right));
+            nodes = parents;
+            height += 1;
         }
-        None
+
+        self.set_root(
+            u32::from_be_bytes(nodes[0].1[..].try_into().unwrap()),
+            height,
+        );
+
+        Ok(())
+    }
+
+    ///
+    /// Every page reachable from the root,
+    /// overflow chains included
+    ///
+    fn live_pages(&self, io: &PageCache) -> Result<Vec<PageId>> {
+        let mut live = Vec::new();
+        let (root, height) = (self.root(), self.height());
+        if root != 0 {
+            self.collect_pages(io, root, height - 1, &mut live)?;
+        }
+
+        Ok(live)
+    }
+
+    fn collect_pages(
+        &self,
+        io: &PageCache,
+        pid: PageId,
+        height: u16,
+        live: &mut Vec<PageId>,
+    ) -> Result<()> {
+        live.push(pid);
+        let page = io.get_page(pid)?;
+
+        for ip in 0..page.get_n_items() {
+            if height > 0 {
+                self.collect_pages(io, page.get_child(ip), height - 1, live)?;
+                continue;
+            }
+
+            let mut next = overflow_head(page.value_at(ip));
+            while next != 0 {
+                live.push(next);
+                next = io.get_chain_page(next)?.get_next();
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Copies pages to their new ids, rewriting every
+    /// child, sibling and overflow pointer to them
+    ///
+    fn relocate(&self, io: &PageCache, moves: &HashMap<PageId, PageId>) -> Result<()> {
+        let (root, height) = (self.root(), self.height());
+        if root != 0 {
+            self.set_root(self.relocate_page(io, root, height - 1, moves)?, height);
+        }
+
+        Ok(())
+    }
+
+    fn relocate_page(
+        &self,
+        io: &PageCache,
+        pid: PageId,
+        height: u16,
+        moves: &HashMap<PageId, PageId>,
+    ) -> Result<PageId> {
+        let moved = |pid: PageId| moves.get(&pid).copied().unwrap_or(pid);
+        let original = io.get_page(pid)?;
+        let mut page = original.clone();
+
+        if height > 0 {
+            for ip in 0..page.get_n_items() {
+                let child = self.relocate_page(io, page.get_child(ip), height - 1, moves)?;
+                page.set_child(ip, child);
+            }
+        } else {
+            page.set_next(moved(page.get_next()));
+            page.set_prev(moved(page.get_prev()));
+
+            let mut items = page.get_items();
+            for (_, stored) in items.iter_mut() {
+                let head = overflow_head(stored);
+                if head != 0 {
+                    self.relocate_chain(io, head, moves)?;
+                    let tail = stored.len() - 4;
+                    stored[tail..].copy_from_slice(&moved(head).to_be_bytes());
+                }
+            }
+            page.set_items(&items);
+        }
+
+        let new = moved(pid);
+        if new != pid || page.as_slice() != original.as_slice() {
+            io.commit_page(new, &page)?;
+        }
+
+        Ok(new)
+    }
+
+    fn relocate_chain(
+        &self,
+        io: &PageCache,
+        mut pid: PageId,
+        moves: &HashMap<PageId, PageId>,
+    ) -> Result<()> {
+        let moved = |pid: PageId| moves.get(&pid).copied().unwrap_or(pid);
+
+        while pid != 0 {
+            let mut page = io.get_chain_page(pid)?;
+            let next = page.get_next();
+
+            if moved(pid) != pid || moved(next) != next {
+                page.set_next(moved(next));
+                io.commit_page(moved(pid), &page)?;
+            }
+
+            pid = next;
+        }
+
+        Ok(())
+    }
+
+    pub fn btree_delete(&self, io: &PageCache, key: &Key) -> Result<bool> {
+        let (mut root, mut height) = (self.root(), self.height());
+        if root == 0 || !self.delete(io, root, key, height - 1)? {
+            return Ok(false);
+        }
+
+        // Collapse root while it only points to a single child
+        while height > 1 {
+            let page = io.get_page(root)?;
+            if page.get_n_items() > 1 {
+                break;
+            }
+
+            info!("Collapsing root at page id {root}");
+            io.free_page(root)?;
+            root = page.get_child(0);
+            height -= 1;
+        }
+        self.set_root(root, height);
+
+        Ok(true)
+    }
+
+    fn delete(&self, io: &PageCache, pid: PageId, key: &Key, height: u16) -> Result<bool> {
+        let mut page = io.get_page(pid)?;
+
+        if height == 0 {
+            if let Ok(ip) = page.search(key) {
+                self.free_value(io, page.value_at(ip))?;
+                page.remove_item(ip);
+                io.commit_page(pid, &page)?;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
+        let ci = page.find_child(key);
+        if !self.delete(io, page.get_child(ci), key, height - 1)? {
+            return Ok(false);
+        }
+
+        if io.get_page(page.get_child(ci))?.get_used() < MIN_FILL {
+            self.rebalance(io, &mut page, ci, height - 1)?;
+            io.commit_page(pid, &page)?;
+        }
+
+        Ok(true)
+    }
+
+    ///
+    /// Fixes an underflowing child by merging it with a
+    /// neighbouring sibling, or evening out items between
+    /// the two when they won't fit on a single page.
+    ///
+    /// Height is that of the children being rebalanced
+    ///
+    fn rebalance(
+        &self,
+        io: &PageCache,
+        parent: &mut PageData,
+        ci: ItemPtr,
+        height: u16,
+    ) -> Result<()> {
+        let n = parent.get_n_items();
+        if n < 2 {
+            return Ok(());
+        }
+
+        // Always work on a left / right pair of neighbours
+        let li = if ci + 1 < n { ci } else { ci - 1 };
+        let sep = parent.key_at(li).to_vec();
+        let lid = parent.get_child(li);
+        let rid = parent.get_child(li + 1);
+
+        let mut left = io.get_page(lid)?;
+        let mut right = io.get_page(rid)?;
+
+        let mut items = left.get_items();
+        if height > 0 {
+            // Pull separator down into the left node's
+            // keyless rightmost child pointer
+            items.last_mut().unwrap().0 = sep.clone();
+        }
+        items.extend(right.get_items());
+
+        if items_fit(&items) {
+            // Right node already has the correct upper
+            // bound in the parent, so merge into it
+            right.set_items(&items);
+
+            if height == 0 {
+                let prev = left.get_prev();
+                right.set_prev(prev);
+                if prev != 0 {
+                    let mut page = io.get_page(prev)?;
+                    page.set_next(rid);
+                    io.commit_page(prev, &page)?;
+                }
+            }
+
+            io.commit_page(rid, &right)?;
+            io.free_page(lid)?;
+            parent.remove_item(li);
+            info!("Merged page {lid} into {rid}");
+
+            return Ok(());
+        }
+
+        let right_items = items.split_off(split_point(&items));
+        let sk = items.last().unwrap().0.clone();
+        if height > 0 {
+            items.last_mut().unwrap().0 = Vec::new();
+        }
+
+        // New separator might not fit where the old one was,
+        // in which case leave the child underfull
+        let lptr = lid.to_be_bytes().to_vec();
+        parent.remove_item(li);
+        if !parent.insert_item(li, &sk, &lptr) {
+            parent.insert_item(li, &sep, &lptr);
+            return Ok(());
+        }
+
+        left.set_items(&items);
+        right.set_items(&right_items);
+
+        io.commit_page(lid, &left)?;
+        io.commit_page(rid, &right)?;
+
+        Ok(())
     }
 }
 
 ///
-/// LRU page buffer caching
+/// First overflow page of a stored leaf
+/// value, 0 if it's stored inline
 ///
-/// Double linked list with hash table
-/// for O(1) access
+fn overflow_head(stored: &[u8]) -> PageId {
+    if stored.first() != Some(&OVERFLOW_VALUE) || stored.len() < 13 {
+        return 0;
+    }
+
+    PageId::from_be_bytes(stored[stored.len() - 4..].try_into().unwrap())
+}
+
 ///
-pub struct PageCache {
-    file: File,
-    capacity: u16, // max # of pages
-    size: u64, // Size in bytes of total db file, loaded on startup
-    pages: Vec<Vec<u8>>,
+/// Gap between two entries, just
+/// before the item pointed to
+///
+struct Position {
+    pid: PageId,
+    page: PageData,
+    ip: ItemPtr,
 }
 
-// TODO: actually implement caching
-impl PageCache {
-    // TODO: store number of bytes in db info struct on load up,
-    // current VERY naive way seeks to end of file
-    fn new_page(&mut self) -> Result<PageId> {
-        let buffer = PageData::new();
-        let len = self.file.seek(SeekFrom::End(0))?;
-        assert_eq!(len % PAGE_SIZE as u64, 0);
+impl Position {
+    ///
+    /// Returns the entry after this position, moving
+    /// on to the start of following leaves if needed
+    ///
+    fn peek_next(&mut self, io: &PageCache) -> Result<Option<(Key, Value)>> {
+        while self.ip >= self.page.get_n_items() {
+            let next = self.page.get_next();
+            if next == 0 {
+                return Ok(None);
+            }
 
-        let pid = (len / PAGE_SIZE as u64) as PageId;
-        self.commit_page(pid, &buffer);
+            self.page = io.get_page(next)?;
+            self.pid = next;
+            self.ip = 0;
+        }
 
-        Ok(pid)
+        Ok(Some(self.page.get_item(self.ip)))
     }
 
     ///
-    /// Returns cached page buffer wrapped as PageData
-    /// or requests the buffer from disk through connection
-    /// object, updates cache and returns it
+    /// Returns the entry before this position, moving
+    /// back to the end of preceding leaves if needed
     ///
-    fn get_page(&mut self, pid: PageId) -> Result<PageData> {
-        let mut data = PageData::new();
-        let offs = pid as u64 * PAGE_SIZE as u64;
-        self.file.read_exact_at(data.as_mut_slice(), offs)?;
+    fn peek_prev(&mut self, io: &PageCache) -> Result<Option<(Key, Value)>> {
+        while self.ip == 0 {
+            let prev = self.page.get_prev();
+            if prev == 0 {
+                return Ok(None);
+            }
 
-        Ok(data)
+            self.page = io.get_page(prev)?;
+            self.pid = prev;
+            self.ip = self.page.get_n_items();
+        }
+
+        Ok(Some(self.page.get_item(self.ip - 1)))
+    }
+}
+
+///
+/// Smallest key greater than every key starting
+/// with the given prefix, if there is one
+///
+fn prefix_end(prefix: &[u8]) -> Bound<Key> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
     }
 
-    fn commit_page(&mut self, pid: PageId, data: &PageData) -> Result<()> {
-        let offs = pid as u64 * PAGE_SIZE as u64;
-        self.size = self.size.max(offs + PAGE_SIZE as u64);
-        // self.size = self.size.max((pid*2) as u64 * PAGE_SIZE as u64);
-        self.file.write_all_at(data.as_slice(), offs)?;
+    Bound::Unbounded
+}
 
-        Ok(())
+fn after_start(start: &Bound<Key>, key: &Key) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn before_end(end: &Bound<Key>, key: &Key) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
     }
 }
 
 ///
-/// User interface object, abstraction
-/// of db operations.
+/// Bidirectional iterator over a range of entries,
+/// following sibling links from leaf to leaf.
 ///
-pub struct Connection {
-    access: BTree,
-    pcache: PageCache,
-    metadata: MetaData,
+/// Entries can be taken from either end, the back
+/// end is only positioned once first used.
+///
+pub struct Cursor<'a> {
+    tree: &'a BTree,
+    io: &'a PageCache,
+    start: Bound<Key>,
+    end: Bound<Key>,
+    // Bounds on entries not yet taken from either end
+    low: Bound<Key>,
+    high: Bound<Key>,
+    front: Option<Position>,
+    back: Option<Position>,
+    done: bool,
 }
 
-impl Connection {
-    pub fn open(db_path: &Path) -> Result<Connection> {
-        // Try intiializing database
-        let (file, meta) = if let Ok(file) = File::options().read(true).write(true).open(db_path) {
-            let mut buffer = vec![0u8; METADATA_SIZE];
-            file.read_exact_at(buffer.as_mut_slice(), 0)?;
-            let meta: MetaData =
-                bincode::decode_from_slice(buffer.as_mut_slice(), BINCODE_CONFIG)?.0;
+impl Cursor<'_> {
+    ///
+    /// Steps backwards over the entry before the front
+    /// of the cursor, so the next call to next returns
+    /// it again
+    ///
+    pub fn prev(&mut self) -> Option<Result<(Key, Value)>> {
+        self.step(Cursor::retreat)
+    }
 
-            info!("Loaded db metadata: {:#?}", meta);
-            (file, meta)
-        } else {
-            let file = File::options()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(db_path)?;
+    fn step<F>(&mut self, f: F) -> Option<Result<(Key, Value)>>
+    where
+        F: FnOnce(&mut Self) -> Result<Option<(Key, Value)>>,
+    {
+        if self.done {
+            return None;
+        }
 
-            let meta = MetaData {
-                magic: MAGIC,
-                size: PAGE_SIZE as u64,
-                root: 0,
-                height: 0,
-            };
+        // Stop for good after an error
+        let result = f(self).transpose();
+        self.done = matches!(result, Some(Err(_)));
 
-            let mut buffer = vec![0u8; PAGE_SIZE];
-            bincode::encode_into_slice(&meta, &mut buffer[..METADATA_SIZE], BINCODE_CONFIG)?;
+        result
+    }
 
-            file.write_all_at(buffer.as_slice(), 0)?;
+    ///
+    /// Decodes the value of an entry taken from the given leaf
+    ///
+    fn load(&mut self, leaf: PageId, entry: Option<(Key, Value)>) -> Result<Option<(Key, Value)>> {
+        entry
+            .map(|(key, value)| Ok((key, self.tree.load_value(self.io, leaf, &value)?)))
+            .transpose()
+    }
 
-            (file, meta)
+    fn seek_start(&mut self) -> Result<Position> {
+        let key = match &self.start {
+            Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+            Bound::Unbounded => Vec::new(),
         };
 
-        Ok(Connection {
-            access: BTree::initialize(&meta),
-            pcache: PageCache {
-                file,
-                capacity: CACHE_CAPACITY,
-                size: meta.size,
-                pages: Vec::new(),
-            },
-            metadata: meta,
-        })
+        // Empty key always sorts first, finding the leftmost leaf
+        let tree = self.tree;
+        let pid = tree.find_leaf(self.io, tree.root(), &key, tree.height() - 1)?;
+        let page = self.io.get_page(pid)?;
+        let ip = match (page.search(&key), &self.start) {
+            (Ok(ip), Bound::Excluded(_)) => ip + 1,
+            (Ok(ip) | Err(ip), _) => ip,
+        };
+
+        Ok(Position { pid, page, ip })
     }
 
-    fn commit_metadata(&mut self) -> Result<()> {
-        self.metadata = MetaData {
-            height: self.access.height,
-            root: self.access.root,
-            size: self.pcache.size,
-            ..self.metadata
+    fn seek_end(&mut self) -> Result<Position> {
+        let tree = self.tree;
+        let (pid, page, ip) = match &self.end {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                let pid = tree.find_leaf(self.io, tree.root(), key, tree.height() - 1)?;
+                let page = self.io.get_page(pid)?;
+                let ip = match (page.search(key), &self.end) {
+                    (Ok(ip), Bound::Included(_)) => ip + 1,
+                    (Ok(ip) | Err(ip), _) => ip,
+                };
+
+                (pid, page, ip)
+            }
+            Bound::Unbounded => {
+                let pid = tree.find_last_leaf(self.io, tree.root(), tree.height() - 1)?;
+                let page = self.io.get_page(pid)?;
+                let ip = page.get_n_items();
+
+                (pid, page, ip)
+            }
         };
 
-        let mut buffer = vec![0u8; METADATA_SIZE];
-        bincode::encode_into_slice(&self.metadata, &mut buffer[..], BINCODE_CONFIG)?;
+        Ok(Position { pid, page, ip })
+    }
 
-        self.pcache.file.write_all_at(buffer.as_slice(), 0)?;
+    fn advance(&mut self) -> Result<Option<(Key, Value)>> {
+        if self.front.is_none() {
+            self.front = Some(self.seek_start()?);
+        }
 
-        Ok(())
+        let pos = self.front.as_mut().unwrap();
+        let entry = pos
+            .peek_next(self.io)?
+            .filter(|(key, _)| before_end(&self.high, key));
+
+        if let Some((key, _)) = &entry {
+            pos.ip += 1;
+            self.low = Bound::Excluded(key.clone());
+        }
+
+        let leaf = pos.pid;
+        self.load(leaf, entry)
     }
 
-    pub fn put(&mut self, key: &Key, value: &Value) -> Result<()> {
-        let result = self.access.btree_insert(&mut self.pcache, key, value);
+    fn retreat(&mut self) -> Result<Option<(Key, Value)>> {
+        if self.front.is_none() {
+            self.front = Some(self.seek_start()?);
+        }
 
-        self.commit_metadata()?;
+        let pos = self.front.as_mut().unwrap();
+        let entry = pos
+            .peek_prev(self.io)?
+            .filter(|(key, _)| after_start(&self.start, key));
 
-        result
+        if let Some((key, _)) = &entry {
+            pos.ip -= 1;
+            self.low = Bound::Included(key.clone());
+        }
+
+        let leaf = pos.pid;
+        self.load(leaf, entry)
     }
 
-    pub fn get(&mut self, key: &Key) -> Result<Value> {
-        let result = self.access.btree_get(&mut self.pcache, key);
-            
-        result
+    fn advance_back(&mut self) -> Result<Option<(Key, Value)>> {
+        if self.back.is_none() {
+            self.back = Some(self.seek_end()?);
+        }
+
+        let pos = self.back.as_mut().unwrap();
+        let entry = pos
+            .peek_prev(self.io)?
+            .filter(|(key, _)| after_start(&self.low, key));
+
+        if let Some((key, _)) = &entry {
+            pos.ip -= 1;
+            self.high = Bound::Excluded(key.clone());
+        }
+
+        let leaf = pos.pid;
+        self.load(leaf, entry)
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(Cursor::advance)
+    }
+}
+
+impl DoubleEndedIterator for Cursor<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(Cursor::advance_back)
+    }
+}
+
+///
+/// Invariant broken somewhere in the database file
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// Page failed its checksum
+    Unreadable { page: u32 },
+    /// Item offset points outside the page
+    BadSlot { page: u32, slot: usize },
+    /// Keys within the page aren't strictly ascending
+    Unsorted { page: u32 },
+    /// Key falls outside the separators the parent bounds it by
+    OutOfBounds { page: u32 },
+    /// Child, sibling or overflow pointer past the end of the file
+    BadPointer { page: u32, target: u32 },
+    /// Page reached a second time
+    SharedPage { page: u32 },
+    /// Leaf found above the leaf level, all leaves
+    /// sit at the depth given by the tree height
+    LeafDepth { page: u32, depth: u16 },
+    /// Internal page without children, or with a
+    /// child pointer that isn't a page id
+    BadChildPointer { page: u32 },
+    /// Metadata has a root without a height, or the other way round
+    RootHeightMismatch { root: u32, height: u16 },
+    /// Leaf value is neither inline nor a valid overflow reference
+    BadValue { page: u32 },
+    /// Overflow chain holds a different number of bytes than recorded
+    BadOverflow { page: u32 },
+    /// Leaf sibling pointers disagree with the order of the tree
+    BrokenSibling { page: u32 },
+    /// Free list length disagrees with the metadata
+    FreeCount { expected: u32, found: u32 },
+    /// Size recorded in the metadata isn't the file length
+    SizeMismatch { expected: u64, found: u64 },
+}
+
+///
+/// Outcome of Connection::check, lists every
+/// violation found rather than stopping at the first
+///
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub pages: u64,
+    pub entries: u64,
+    pub violations: Vec<Violation>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+///
+/// Walks every page reachable from the metadata,
+/// recording violations instead of failing on them
+///
+struct Checker<'a> {
+    io: &'a PageCache,
+    n_pages: u64,
+    seen: HashSet<PageId>,
+    leaves: Vec<(PageId, PageId, PageId)>, // id, prev, next
+    report: CheckReport,
+}
+
+impl Checker<'_> {
+    ///
+    /// Marks a page as reached from the given one, false
+    /// if it shouldn't be visited
+    ///
+    fn claim(&mut self, from: PageId, pid: PageId) -> bool {
+        if pid == 0 || pid as u64 >= self.n_pages {
+            let violation = Violation::BadPointer {
+                page: from,
+                target: pid,
+            };
+            self.report.violations.push(violation);
+            return false;
+        }
+        if !self.seen.insert(pid) {
+            self.report
+                .violations
+                .push(Violation::SharedPage { page: pid });
+            return false;
+        }
+
+        self.report.pages += 1;
+        true
+    }
+
+    ///
+    /// Loads a page, a failed checksum is recorded and
+    /// yields None while other errors are returned
+    ///
+    fn load(&mut self, pid: PageId) -> Result<Option<PageData>> {
+        // Layout is checked along with everything else
+        match self.io.load_page(pid, |_| true) {
+            Ok(page) => Ok(Some(page)),
+            Err(Error::Corrupt { page }) => {
+                self.report.violations.push(Violation::Unreadable { page });
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    ///
+    /// Checks a page and its subtree, every key in it must
+    /// be greater than low and no greater than high
+    ///
+    fn check_page(
+        &mut self,
+        pid: PageId,
+        height: u16,
+        depth: u16,
+        low: Option<&[u8]>,
+        high: Option<&[u8]>,
+    ) -> Result<()> {
+        let Some(page) = self.load(pid)? else {
+            return Ok(());
+        };
+        if !self.check_slots(pid, &page) {
+            return Ok(());
+        }
+
+        // Keyless rightmost child pointer isn't bounded
+        let n_items = page.get_n_items();
+        let keyed = if height == 0 {
+            n_items
+        } else {
+            n_items.saturating_sub(1)
+        };
+        if (1..keyed).any(|ip| page.key_at(ip - 1) >= page.key_at(ip)) {
+            self.report
+                .violations
+                .push(Violation::Unsorted { page: pid });
+        }
+        let in_bounds =
+            |key: &[u8]| low.is_none_or(|low| key > low) && high.is_none_or(|high| key <= high);
+        if !(0..keyed).all(|ip| in_bounds(page.key_at(ip))) {
+            self.report
+                .violations
+                .push(Violation::OutOfBounds { page: pid });
+        }
+
+        if height == 0 {
+            for ip in 0..n_items {
+                self.report.entries += 1;
+                self.check_value(pid, page.value_at(ip))?;
+            }
+            self.leaves.push((pid, page.get_prev(), page.get_next()));
+            return Ok(());
+        }
+
+        // Internal pages always end in a keyless item, leaves don't
+        if n_items > 0 && !page.key_at(n_items - 1).is_empty() {
+            self.report
+                .violations
+                .push(Violation::LeafDepth { page: pid, depth });
+            return Ok(());
+        }
+        if n_items == 0 || (0..n_items).any(|ip| page.value_at(ip).len() != 4) {
+            self.report
+                .violations
+                .push(Violation::BadChildPointer { page: pid });
+            return Ok(());
+        }
+
+        for ip in 0..n_items {
+            let child = page.get_child(ip);
+            let low = if ip == 0 {
+                low
+            } else {
+                Some(page.key_at(ip - 1))
+            };
+            let high = if ip == n_items - 1 {
+                high
+            } else {
+                Some(page.key_at(ip))
+            };
+            if self.claim(pid, child) {
+                self.check_page(child, height - 1, depth + 1, low, high)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_slots(&mut self, pid: PageId, page: &PageData) -> bool {
+        match page.find_bad_slot() {
+            Some(slot) => {
+                let violation = Violation::BadSlot { page: pid, slot };
+                self.report.violations.push(violation);
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn check_value(&mut self, leaf: PageId, stored: &[u8]) -> Result<()> {
+        match stored.split_first() {
+            Some((&INLINE_VALUE, _)) => Ok(()),
+            Some((&OVERFLOW_VALUE, rest)) if rest.len() >= 12 => {
+                let (prefix, tail) = rest.split_at(rest.len() - 12);
+                let len = u64::from_be_bytes(tail[..8].try_into().unwrap());
+                let mut found = prefix.len() as u64;
+
+                let (mut from, mut pid) = (leaf, overflow_head(stored));
+                while found < len && self.claim(from, pid) {
+                    let Some(page) = self.load(pid)? else {
+                        return Ok(());
+                    };
+                    if page.get_n_items() > OVERFLOW_CHUNK {
+                        break;
+                    }
+                    found += page.get_n_items() as u64;
+                    (from, pid) = (pid, page.get_next());
+                }
+
+                if found != len {
+                    self.report
+                        .violations
+                        .push(Violation::BadOverflow { page: leaf });
+                }
+
+                Ok(())
+            }
+            _ => {
+                self.report
+                    .violations
+                    .push(Violation::BadValue { page: leaf });
+                Ok(())
+            }
+        }
+    }
+
+    ///
+    /// Leaves were visited in key order, each
+    /// has to link to its neighbours in it
+    ///
+    fn check_siblings(&mut self) {
+        for (i, &(pid, prev, next)) in self.leaves.iter().enumerate() {
+            let expected_prev = if i == 0 { 0 } else { self.leaves[i - 1].0 };
+            let expected_next = self.leaves.get(i + 1).map_or(0, |leaf| leaf.0);
+            if prev != expected_prev || next != expected_next {
+                let violation = Violation::BrokenSibling { page: pid };
+                self.report.violations.push(violation);
+            }
+        }
+    }
+
+    fn check_free_list(&mut self, head: PageId, expected: u32) -> Result<()> {
+        let mut found = 0;
+        let (mut from, mut pid) = (0, head);
+        while pid != 0 && self.claim(from, pid) {
+            let Some(page) = self.load(pid)? else {
+                break;
+            };
+            found += 1;
+            (from, pid) = (pid, page.get_next());
+        }
+
+        if found != expected {
+            let violation = Violation::FreeCount { expected, found };
+            self.report.violations.push(violation);
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// What a page is used for, pages don't record it
+/// so it's worked out from the tree and free list
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    Meta,
+    Internal,
+    Leaf,
+    Overflow,
+    Free,
+    /// Not reachable from the metadata at all
+    Unreachable,
+}
+
+///
+/// Metadata header at the start of the file
+///
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u16,
+    pub size: u64,
+    pub root: u32,
+    pub height: u16,
+    pub free_head: u32,
+    pub free_pages: u32,
+}
+
+impl From<&MetaData> for Header {
+    fn from(meta: &MetaData) -> Header {
+        Header {
+            version: meta.version,
+            size: meta.size,
+            root: meta.root,
+            height: meta.height,
+            free_head: meta.free_head,
+            free_pages: meta.free_pages,
+        }
+    }
+}
+
+///
+/// Item value as stored in a tree page
+///
+#[derive(Debug, Clone)]
+pub enum ItemValue {
+    Child(u32),
+    Inline(Vec<u8>),
+    Overflow {
+        prefix: Vec<u8>,
+        len: u64,
+        head: u32,
+    },
+    /// Doesn't decode as anything the page kind allows
+    Invalid(Vec<u8>),
+}
+
+///
+/// Decoded contents of a single page
+///
+#[derive(Debug, Clone)]
+pub struct PageDump {
+    pub id: u32,
+    pub kind: PageKind,
+    /// Always set for version 1 files, which have no checksums
+    pub checksum_ok: bool,
+    /// Holds the chunk length in overflow pages
+    pub n_items: usize,
+    pub free: usize,
+    pub next: u32,
+    pub prev: u32,
+    /// First item whose offset points outside the page
+    pub bad_slot: Option<usize>,
+    pub items: Vec<(Vec<u8>, ItemValue)>,
+    pub chunk: Vec<u8>,
+}
+
+///
+/// Read only view of a database file for debugging, never
+/// writes to it and doesn't replay the write ahead log
+///
+/// Also opens version 1 files without upgrading them, their
+/// pages have no checksums, sibling links or overflow chains
+///
+pub struct Inspector {
+    file: Box<dyn VfsFile>,
+    metadata: MetaData,
+    kinds: HashMap<PageId, PageKind>,
+}
+
+impl Inspector {
+    pub fn open(db_path: &Path) -> Result<Inspector> {
+        let file = std::fs::File::open(db_path)?;
+        let metadata = read_metadata(&file)?;
+        if metadata.version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: metadata.version,
+                supported: FORMAT_VERSION,
+            });
+        }
+
+        let mut inspector = Inspector {
+            file: Box::new(file),
+            metadata,
+            kinds: HashMap::from([(0, PageKind::Meta)]),
+        };
+        inspector.classify()?;
+
+        Ok(inspector)
+    }
+
+    pub fn header(&self) -> Header {
+        Header::from(&self.metadata)
+    }
+
+    ///
+    /// Number of pages in the file, which may differ
+    /// from the metadata if it is damaged
+    ///
+    pub fn n_pages(&self) -> Result<u32> {
+        Ok((self.file.size()? / PAGE_SIZE as u64) as u32)
+    }
+
+    pub fn kind(&self, pid: u32) -> PageKind {
+        self.kinds
+            .get(&pid)
+            .copied()
+            .unwrap_or(PageKind::Unreachable)
+    }
+
+    pub fn page(&self, pid: u32) -> Result<PageDump> {
+        if pid >= self.n_pages()? {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Page {pid} is past the end of the file"),
+            );
+            return Err(err.into());
+        }
+
+        let page = self.read(pid)?;
+        let kind = self.kind(pid);
+        let (raw_items, bad_slot) = match kind {
+            PageKind::Internal | PageKind::Leaf => self.raw_items(&page),
+            _ => (Vec::new(), None),
+        };
+
+        let mut items = Vec::new();
+        for (key, value) in raw_items {
+            let value = match kind {
+                PageKind::Internal if value.len() == 4 => {
+                    ItemValue::Child(PageId::from_be_bytes(value.try_into().unwrap()))
+                }
+                PageKind::Leaf if self.is_v1() => ItemValue::Inline(value),
+                PageKind::Leaf => decode_value(&value),
+                _ => ItemValue::Invalid(value),
+            };
+            items.push((key, value));
+        }
+
+        if self.is_v1() {
+            let n_items = upgrade::n_items(page.as_slice());
+            return Ok(PageDump {
+                id: pid,
+                kind,
+                checksum_ok: true,
+                n_items,
+                free: upgrade::free(page.as_slice()),
+                next: 0,
+                prev: 0,
+                bad_slot,
+                items,
+                chunk: Vec::new(),
+            });
+        }
+
+        let chunk = match kind {
+            PageKind::Overflow if page.get_n_items() <= OVERFLOW_CHUNK => page.get_chunk().to_vec(),
+            _ => Vec::new(),
+        };
+
+        Ok(PageDump {
+            id: pid,
+            kind,
+            checksum_ok: page.verify_checksum(),
+            n_items: page.get_n_items(),
+            free: page.get_free(),
+            next: page.get_next(),
+            prev: page.get_prev(),
+            bad_slot,
+            items,
+            chunk,
+        })
+    }
+
+    ///
+    /// Files from before checksums and overflow pages,
+    /// their pages are decoded with the original layout
+    ///
+    fn is_v1(&self) -> bool {
+        self.metadata.version == 1
+    }
+
+    ///
+    /// Keys and stored values of a tree page, or
+    /// nothing and the first bad slot if it has one
+    ///
+    fn raw_items(&self, page: &PageData) -> (Vec<(Key, Value)>, Option<ItemPtr>) {
+        if self.is_v1() {
+            let page = page.as_slice();
+            let mut items = Vec::new();
+            for ip in 0..upgrade::n_items(page) {
+                match upgrade::item(page, ip, 0) {
+                    Ok((key, value)) => items.push((key.to_vec(), value.to_vec())),
+                    Err(_) => return (Vec::new(), Some(ip)),
+                }
+            }
+            return (items, None);
+        }
+
+        if let Some(ip) = page.find_bad_slot() {
+            return (Vec::new(), Some(ip));
+        }
+        let items = (0..page.get_n_items())
+            .map(|ip| (page.key_at(ip).to_vec(), page.value_at(ip).to_vec()))
+            .collect();
+
+        (items, None)
+    }
+
+    ///
+    /// Ids of the given page and every page below it,
+    /// overflow chains included, parents first
+    ///
+    pub fn subtree(&self, pid: u32) -> Result<Vec<u32>> {
+        let mut pages = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![pid];
+        while let Some(pid) = stack.pop() {
+            if !seen.insert(pid) {
+                continue;
+            }
+            pages.push(pid);
+
+            let dump = self.page(pid)?;
+            let mut below = Vec::new();
+            for (_, value) in &dump.items {
+                match *value {
+                    ItemValue::Child(child) => below.push(child),
+                    ItemValue::Overflow { head, .. } => below.push(head),
+                    _ => {}
+                }
+            }
+            if dump.kind == PageKind::Overflow {
+                below.push(dump.next);
+            }
+
+            // Only follow pointers the walk from the root agrees with
+            let expected = |pid: &u32| match self.kind(*pid) {
+                PageKind::Internal | PageKind::Leaf => dump.kind == PageKind::Internal,
+                PageKind::Overflow => dump.kind != PageKind::Internal,
+                _ => false,
+            };
+            stack.extend(below.into_iter().filter(expected).rev());
+        }
+
+        Ok(pages)
+    }
+
+    fn read(&self, pid: PageId) -> Result<PageData> {
+        let mut page = PageData::new();
+        self.file
+            .read_exact_at(page.as_mut_slice(), pid as u64 * PAGE_SIZE as u64)?;
+
+        Ok(page)
+    }
+
+    ///
+    /// Walks the tree and free list the way a connection
+    /// would, skipping anything that doesn't decode
+    ///
+    fn classify(&mut self) -> Result<()> {
+        let n_pages = self.n_pages()?;
+        if self.metadata.root != 0 {
+            self.classify_tree(self.metadata.root, self.metadata.height - 1, n_pages)?;
+        }
+
+        let mut pid = self.metadata.free_head;
+        while pid != 0 && pid < n_pages && !self.kinds.contains_key(&pid) {
+            self.kinds.insert(pid, PageKind::Free);
+            pid = self.read(pid)?.get_next();
+        }
+
+        Ok(())
+    }
+
+    fn classify_tree(&mut self, pid: PageId, height: u16, n_pages: u32) -> Result<()> {
+        if pid == 0 || pid >= n_pages || self.kinds.contains_key(&pid) {
+            return Ok(());
+        }
+
+        let page = self.read(pid)?;
+        let kind = if height == 0 {
+            PageKind::Leaf
+        } else {
+            PageKind::Internal
+        };
+        self.kinds.insert(pid, kind);
+        let (items, _) = self.raw_items(&page);
+        for (_, value) in items {
+            if height > 0 {
+                if let Ok(child) = value.try_into() {
+                    self.classify_tree(PageId::from_be_bytes(child), height - 1, n_pages)?;
+                }
+                continue;
+            }
+            if self.is_v1() {
+                continue;
+            }
+
+            let mut next = overflow_head(&value);
+            while next != 0 && next < n_pages && !self.kinds.contains_key(&next) {
+                self.kinds.insert(next, PageKind::Overflow);
+                next = self.read(next)?.get_next();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_value(stored: &[u8]) -> ItemValue {
+    match stored.split_first() {
+        Some((&INLINE_VALUE, value)) => ItemValue::Inline(value.to_vec()),
+        Some((&OVERFLOW_VALUE, rest)) if rest.len() >= 12 => {
+            let (prefix, tail) = rest.split_at(rest.len() - 12);
+            ItemValue::Overflow {
+                prefix: prefix.to_vec(),
+                len: u64::from_be_bytes(tail[..8].try_into().unwrap()),
+                head: overflow_head(stored),
+            }
+        }
+        _ => ItemValue::Invalid(stored.to_vec()),
+    }
+}
+
+///
+/// Cached copy of a single page
+///
+struct Frame {
+    pid: PageId,
+    page: PageData,
+    dirty: bool,              // differs from the database file
+    logged: bool,             // appended to the write ahead log
+    before: Option<PageData>, // last logged image, for rollback
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+///
+/// LRU page buffer caching
+///
+/// Double linked list with hash table
+/// for O(1) access
+///
+/// Frames and page allocation are behind locks so inserts
+/// and readers can share the cache, anything with it to
+/// itself gets at them without locking
+///
+pub struct PageCache {
+    file: Box<dyn VfsFile>,
+    alloc: Mutex<Alloc>,
+    lru: Mutex<Lru>,
+    latches: Latches,
+}
+
+struct Alloc {
+    size: u64, // Size in bytes of total db file, loaded on startup
+    free_head: PageId,
+    free_pages: u32,
+}
+
+struct Lru {
+    capacity: usize, // max # of pages
+    frames: Vec<Frame>,
+    free: Vec<usize>, // Frames emptied by a rollback
+    table: HashMap<PageId, usize>,
+    head: Option<usize>, // Most recently used
+    tail: Option<usize>, // Least recently used
+}
+
+impl PageCache {
+    fn new(file: Box<dyn VfsFile>, capacity: usize, meta: &MetaData) -> PageCache {
+        let lru = Lru {
+            capacity: capacity.max(1),
+            frames: Vec::new(),
+            free: Vec::new(),
+            table: HashMap::new(),
+            head: None,
+            tail: None,
+        };
+
+        let alloc = Alloc {
+            size: meta.size,
+            free_head: meta.free_head,
+            free_pages: meta.free_pages,
+        };
+
+        PageCache {
+            file,
+            alloc: Mutex::new(alloc),
+            lru: Mutex::new(lru),
+            latches: Latches::default(),
+        }
+    }
+
+    fn alloc(&mut self) -> &mut Alloc {
+        self.alloc.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_alloc(&self) -> MutexGuard<'_, Alloc> {
+        self.alloc.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///
+    /// Size in bytes the database file
+    /// will have once written back
+    ///
+    fn size(&self) -> u64 {
+        self.lock_alloc().size
+    }
+
+    ///
+    /// Size in bytes the database file would have if
+    /// every dirty page were written back right now
+    ///
+    fn written_size(&self) -> Result<u64> {
+        let end = self
+            .lock()
+            .frames
+            .iter()
+            .filter(|frame| frame.dirty)
+            .map(|frame| (frame.pid as u64 + 1) * PAGE_SIZE as u64)
+            .max();
+
+        Ok(self.file.size()?.max(end.unwrap_or(0)))
+    }
+
+    ///
+    /// Frames of a cache nobody else is reading
+    ///
+    fn lru(&mut self) -> &mut Lru {
+        self.lru.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///
+    /// Frames of a cache readers may be sharing, a reader
+    /// panicking with the lock held leaves them intact
+    ///
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///
+    /// Reuses a page off the free list, or allocates
+    /// one past the end of the database when it's empty
+    ///
+    fn new_page(&self) -> Result<PageId> {
+        let mut alloc = self.lock_alloc();
+        let pid = if alloc.free_head != 0 {
+            let pid = alloc.free_head;
+            alloc.free_head = self.get_chain_page(pid)?.get_next();
+            alloc.free_pages -= 1;
+            pid
+        } else {
+            (alloc.size / PAGE_SIZE as u64) as PageId
+        };
+        alloc.size = alloc.size.max((pid as u64 + 1) * PAGE_SIZE as u64);
+
+        let buffer = PageData::new();
+        self.commit_page(pid, &buffer)?;
+
+        Ok(pid)
+    }
+
+    ///
+    /// Pushes a page no longer in use onto the free
+    /// list, linked through its next sibling pointer
+    ///
+    fn free_page(&self, pid: PageId) -> Result<()> {
+        let mut alloc = self.lock_alloc();
+        let mut page = PageData::new();
+        page.set_next(alloc.free_head);
+        self.commit_page(pid, &page)?;
+
+        alloc.free_head = pid;
+        alloc.free_pages += 1;
+
+        Ok(())
+    }
+
+    ///
+    /// Reads a page straight from disk, skipping
+    /// both the cache and checksum verification
+    ///
+    pub(crate) fn read_raw(&self, pid: PageId) -> Result<Vec<u8>> {
+        let mut page = vec![0u8; PAGE_SIZE];
+        self.file
+            .read_exact_at(&mut page, pid as u64 * PAGE_SIZE as u64)?;
+
+        Ok(page)
+    }
+
+    ///
+    /// Returns cached page buffer wrapped as PageData
+    /// or requests the buffer from disk through connection
+    /// object, updates cache and returns it
+    ///
+    /// Frames aren't locked during the read, readers missing
+    /// the same page may both load it but only one is cached
+    ///
+    fn get_page(&self, pid: PageId) -> Result<PageData> {
+        self.load_page(pid, |page| page.find_bad_slot().is_none())
+    }
+
+    ///
+    /// Like get_page for overflow and free list pages,
+    /// which hold a chunk of bytes instead of items
+    ///
+    fn get_chain_page(&self, pid: PageId) -> Result<PageData> {
+        self.load_page(pid, |page| page.get_n_items() <= OVERFLOW_CHUNK)
+    }
+
+    ///
+    /// Pages read from disk have to pass their checksum and
+    /// the given layout check, so a page written wrong before
+    /// its checksum was stamped can't index outside itself
+    ///
+    fn load_page(&self, pid: PageId, valid: impl Fn(&PageData) -> bool) -> Result<PageData> {
+        if let Some(page) = self.lock().get(pid) {
+            return Ok(page);
+        }
+
+        let mut data = PageData::new();
+        let offs = pid as u64 * PAGE_SIZE as u64;
+        self.file.read_exact_at(data.as_mut_slice(), offs)?;
+        if !data.verify_checksum() || !valid(&data) {
+            return Err(Error::Corrupt { page: pid });
+        }
+
+        let mut lru = self.lock();
+        if !lru.table.contains_key(&pid) {
+            lru.insert_frame(pid, data.clone(), false);
+        }
+
+        Ok(data)
+    }
+
+    ///
+    /// Updates the cached page, it is only written
+    /// to disk once evicted or flushed
+    ///
+    fn commit_page(&self, pid: PageId, data: &PageData) -> Result<()> {
+        let mut data = data.clone();
+        data.update_checksum();
+
+        let mut lru = self.lock();
+        if let Some(&idx) = lru.table.get(&pid) {
+            let frame = &mut lru.frames[idx];
+            let old = std::mem::replace(&mut frame.page, data);
+            if frame.dirty && frame.logged {
+                frame.before = Some(old);
+            }
+            frame.dirty = true;
+            frame.logged = false;
+            lru.touch(idx);
+        } else {
+            lru.insert_frame(pid, data, true);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Cuts the database file down to the given number
+    /// of pages, dropping any cached past the end
+    ///
+    fn truncate(&mut self, n_pages: u64) -> Result<()> {
+        let lru = self.lru();
+        for idx in 0..lru.frames.len() {
+            let pid = lru.frames[idx].pid;
+            if pid as u64 >= n_pages && lru.table.get(&pid) == Some(&idx) {
+                lru.frames[idx].dirty = false;
+                lru.evict(idx);
+                lru.free.push(idx);
+            }
+        }
+
+        let size = n_pages * PAGE_SIZE as u64;
+        self.alloc().size = size;
+        self.file.set_len(size)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Pages changed since they were last logged
+    ///
+    fn unlogged_pages(&mut self) -> Vec<(PageId, &[u8])> {
+        self.lru()
+            .frames
+            .iter()
+            .filter(|frame| !frame.logged)
+            .map(|frame| (frame.pid, frame.page.as_slice()))
+            .collect()
+    }
+
+    fn has_unlogged(&mut self) -> bool {
+        self.lru().frames.iter().any(|frame| !frame.logged)
+    }
+
+    fn mark_logged(&mut self) {
+        for frame in self.lru().frames.iter_mut() {
+            frame.logged = true;
+            frame.before = None;
+        }
+    }
+
+    ///
+    /// Undoes every change made since pages were last
+    /// logged, pages that matched the database file
+    /// are dropped to be read again
+    ///
+    fn discard_unlogged(&mut self) {
+        let lru = self.lru();
+        for idx in 0..lru.frames.len() {
+            let frame = &mut lru.frames[idx];
+            if frame.logged {
+                continue;
+            }
+
+            frame.logged = true;
+            if let Some(page) = frame.before.take() {
+                frame.page = page;
+            } else {
+                frame.dirty = false;
+                lru.evict(idx);
+                lru.free.push(idx);
+            }
+        }
+    }
+
+    ///
+    /// Set once dirty pages pinned in the
+    /// cache push it past its capacity
+    ///
+    fn over_capacity(&mut self) -> bool {
+        let lru = self.lru();
+        lru.table.len() > lru.capacity
+    }
+
+    ///
+    /// Writes every dirty page back to the database
+    /// file, they must have been logged first
+    ///
+    fn flush(&mut self) -> Result<()> {
+        let PageCache { file, lru, .. } = self;
+        let lru = lru.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for frame in lru.frames.iter_mut().filter(|frame| frame.dirty) {
+            let offs = frame.pid as u64 * PAGE_SIZE as u64;
+            file.write_all_at(frame.page.as_slice(), offs)?;
+            frame.dirty = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl Lru {
+    fn get(&mut self, pid: PageId) -> Option<PageData> {
+        let idx = *self.table.get(&pid)?;
+        self.touch(idx);
+
+        Some(self.frames[idx].page.clone())
+    }
+
+    ///
+    /// Caches a page not yet in the table, evicting the
+    /// least recently used clean page once at capacity
+    ///
+    /// Dirty pages are never evicted, they only reach the
+    /// database file once logged, so the cache may grow
+    /// past capacity until they are written back
+    ///
+    fn insert_frame(&mut self, pid: PageId, page: PageData, dirty: bool) {
+        let frame = Frame {
+            pid,
+            page,
+            dirty,
+            logged: !dirty,
+            before: None,
+            prev: None,
+            next: None,
+        };
+
+        let idx = if let Some(idx) = self.free.pop() {
+            self.frames[idx] = frame;
+            idx
+        } else if let Some(idx) = self.find_victim() {
+            self.evict(idx);
+            self.frames[idx] = frame;
+            idx
+        } else {
+            self.frames.push(frame);
+            self.frames.len() - 1
+        };
+
+        self.table.insert(pid, idx);
+        self.push_front(idx);
+    }
+
+    ///
+    /// Least recently used page matching the database
+    /// file, once the cache is at capacity
+    ///
+    fn find_victim(&self) -> Option<usize> {
+        if self.table.len() < self.capacity {
+            return None;
+        }
+
+        let mut cur = self.tail;
+        while let Some(idx) = cur {
+            if !self.frames[idx].dirty {
+                return Some(idx);
+            }
+            cur = self.frames[idx].prev;
+        }
+
+        None
+    }
+
+    fn evict(&mut self, idx: usize) {
+        self.table.remove(&self.frames[idx].pid);
+        self.unlink(idx);
+    }
+
+    ///
+    /// Marks a frame as most recently used
+    ///
+    fn touch(&mut self, idx: usize) {
+        if self.head != Some(idx) {
+            self.unlink(idx);
+            self.push_front(idx);
+        }
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        self.frames[idx].prev = None;
+        self.frames[idx].next = self.head;
+
+        match self.head {
+            Some(head) => self.frames[head].prev = Some(idx),
+            None => self.tail = Some(idx),
+        }
+        self.head = Some(idx);
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let Frame { prev, next, .. } = self.frames[idx];
+
+        match prev {
+            Some(prev) => self.frames[prev].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.frames[next].prev = prev,
+            None => self.tail = prev,
+        }
+
+        self.frames[idx].prev = None;
+        self.frames[idx].next = None;
+    }
+}
+
+///
+/// Read/write latch on each page an insert is working on,
+/// only held for the length of a single insert
+///
+/// Page 0 stands in for the root pointer. Latches are taken
+/// from the root down and left to right along leaves, never
+/// the other way, so inserts can't deadlock
+///
+#[derive(Default)]
+struct Latches {
+    held: Mutex<HashMap<PageId, LatchState>>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LatchState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+impl Latches {
+    fn read(&self, pid: PageId) -> Latch<'_> {
+        self.acquire(pid, false)
+    }
+
+    fn write(&self, pid: PageId) -> Latch<'_> {
+        self.acquire(pid, true)
+    }
+
+    ///
+    /// Blocks until the latch is free, waiting writers
+    /// go ahead of readers that arrive after them
+    ///
+    fn acquire(&self, pid: PageId, write: bool) -> Latch<'_> {
+        let mut held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+        if write {
+            held.entry(pid).or_default().waiting_writers += 1;
+        }
+
+        loop {
+            let state = held.entry(pid).or_default();
+            if write && !state.writer && state.readers == 0 {
+                state.waiting_writers -= 1;
+                state.writer = true;
+                break;
+            }
+            if !write && !state.writer && state.waiting_writers == 0 {
+                state.readers += 1;
+                break;
+            }
+
+            held = self
+                .released
+                .wait(held)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        Latch {
+            latches: self,
+            pid,
+            write,
+        }
+    }
+}
+
+///
+/// Held latch, released when dropped
+///
+struct Latch<'a> {
+    latches: &'a Latches,
+    pid: PageId,
+    write: bool,
+}
+
+impl Drop for Latch<'_> {
+    fn drop(&mut self) {
+        let mut held = self
+            .latches
+            .held
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(state) = held.get_mut(&self.pid) {
+            if self.write {
+                state.writer = false;
+            } else {
+                state.readers -= 1;
+            }
+            if !state.writer && state.readers == 0 && state.waiting_writers == 0 {
+                held.remove(&self.pid);
+            }
+        }
+
+        drop(held);
+        self.latches.released.notify_all();
+    }
+}
+
+///
+/// Settings applied when opening a database
+///
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Max number of pages held in memory
+    pub cache_capacity: usize,
+    /// When commits are synced to disk, every
+    /// one of them unless told otherwise
+    pub durability: Durability,
+    /// Where the database and its log are stored
+    pub vfs: Arc<dyn Vfs>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            cache_capacity: CACHE_CAPACITY,
+            durability: Durability::OnCommit,
+            vfs: Arc::new(OsVfs),
+        }
+    }
+}
+
+///
+/// When the write ahead log is synced, a commit only
+/// survives losing power once it has been
+///
+/// Whichever policy is picked, the log is synced before
+/// pages are written back in place and the database file
+/// before the log is emptied, so a crash never tears the tree
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Left to the OS and write backs
+    None,
+    /// Every commit, the slowest but loses nothing,
+    /// the default
+    OnCommit,
+    /// The first commit once the interval has passed
+    /// since the last sync. There's no background thread,
+    /// so the last commits before the database goes idle
+    /// stay unsynced until the next commit, a sync() or
+    /// the connection closing
+    Periodic(Duration),
+}
+
+///
+/// User interface object, abstraction
+/// of db operations.
+///
+pub struct Connection {
+    access: BTree,
+    pcache: PageCache,
+    wal: Wal,
+    metadata: MetaData,
+    durability: Durability,
+    last_sync: Instant,
+}
+
+impl Connection {
+    pub fn open(db_path: &Path) -> Result<Connection> {
+        Connection::open_with(db_path, Options::default())
+    }
+
+    pub fn open_with(db_path: &Path, options: Options) -> Result<Connection> {
+        let vfs = options.vfs;
+        let wal_path = Wal::path_for(db_path);
+        let file = vfs.open(db_path, true)?;
+
+        // Bring the file up to the last commit before reading it
+        Wal::recover(&*vfs, &wal_path, &*file)?;
+
+        // Try intiializing database
+        let size = file.size()?;
+        let meta = if size > 0 {
+            let meta = read_metadata(&*file)?;
+            info!("Loaded db metadata: {:#?}", meta);
+
+            // Finishes what a crash cut short: creating the file
+            // partway through its header page, or a vacuum before
+            // it truncated the file. Older formats are left alone
+            let torn_header = size < PAGE_SIZE as u64 && meta.size == PAGE_SIZE as u64;
+            if meta.version == FORMAT_VERSION && (torn_header || size > meta.size) {
+                file.set_len(meta.size)?;
+                file.sync_data()?;
+            }
+
+            meta
+        } else {
+            let meta = MetaData {
+                magic: MAGIC,
+                version: FORMAT_VERSION,
+                size: PAGE_SIZE as u64,
+                root: 0,
+                height: 0,
+                free_head: 0,
+                free_pages: 0,
+            };
+
+            let mut buffer = encode_metadata(&meta)?;
+            buffer.resize(PAGE_SIZE, 0);
+            file.write_all_at(buffer.as_slice(), 0)?;
+            file.sync_data()?;
+
+            meta
+        };
+
+        let wal = Wal::create(vfs, wal_path)?;
+
+        let mut connection = Connection {
+            access: BTree::initialize(&meta),
+            pcache: PageCache::new(file, options.cache_capacity, &meta),
+            wal,
+            metadata: meta,
+            durability: options.durability,
+            last_sync: Instant::now(),
+        };
+
+        if connection.metadata.version < FORMAT_VERSION {
+            connection.upgrade()?;
+        }
+
+        Ok(connection)
+    }
+
+    ///
+    /// Migrates an older format database in place by rebuilding
+    /// its tree in pages past the end of the file, switching over
+    /// in a single commit then vacuuming the old pages away
+    ///
+    /// Only version 1 exists before the current one, its pages
+    /// are read straight off disk as they carry no checksum
+    ///
+    fn upgrade(&mut self) -> Result<()> {
+        info!(
+            "Upgrading database from format version {} to {FORMAT_VERSION}",
+            self.metadata.version
+        );
+
+        // Nothing of the old tree is reused, and version 1 wrote
+        // pages in place so some may lie past the size it recorded
+        let (root, height) = (self.metadata.root, self.metadata.height);
+        let file_size = self.pcache.file.size()?.next_multiple_of(PAGE_SIZE as u64);
+        self.access = BTree::new(0, 0);
+        self.pcache.alloc().size = self.metadata.size.max(file_size);
+
+        if let Err(err) = self.migrate(root, height) {
+            self.rollback();
+            return Err(err);
+        }
+        self.vacuum()?;
+
+        Ok(())
+    }
+
+    fn migrate(&mut self, root: PageId, height: u16) -> Result<()> {
+        let access = &self.access;
+        upgrade::for_each_entry(&mut self.pcache, root, height, |io, key, value| {
+            check_key(&key)?;
+
+            // Version 1 put a key in front of an older entry for
+            // it rather than replacing it, the first one found wins
+            if access.btree_get(io, &key)?.is_none() {
+                access.btree_insert(io, &key, &value)?;
+            }
+
+            Ok(())
+        })?;
+
+        self.commit_metadata()
+    }
+
+    ///
+    /// Logs every page changed since the last commit,
+    /// along with the metadata pointing at them
+    ///
+    fn commit_metadata(&mut self) -> Result<()> {
+        // Pages are only ever written in the current format
+        let alloc = self.pcache.alloc();
+        let metadata = MetaData {
+            version: FORMAT_VERSION,
+            size: alloc.size,
+            free_head: alloc.free_head,
+            free_pages: alloc.free_pages,
+            height: self.access.height(),
+            root: self.access.root(),
+            ..self.metadata
+        };
+
+        let header = encode_metadata(&metadata)?;
+        self.wal.append(&self.pcache.unlogged_pages(), &header)?;
+        self.pcache.mark_logged();
+        self.metadata = metadata;
+
+        match self.durability {
+            Durability::None => {}
+            Durability::OnCommit => self.sync()?,
+            Durability::Periodic(interval) => {
+                if self.last_sync.elapsed() >= interval {
+                    self.sync()?;
+                }
+            }
+        }
+
+        if self.wal.len() > WAL_CHECKPOINT {
+            self.checkpoint()?;
+        } else if self.pcache.over_capacity() {
+            self.write_back()?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Makes every commit so far durable, whatever
+    /// the durability policy
+    ///
+    pub fn sync(&mut self) -> Result<()> {
+        self.wal.sync()?;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    ///
+    /// Writes logged pages back in place, the log has to
+    /// reach disk first so torn writes can be redone
+    ///
+    fn write_back(&mut self) -> Result<()> {
+        self.wal.sync()?;
+        self.pcache.flush()?;
+        self.pcache
+            .file
+            .write_all_at(&encode_metadata(&self.metadata)?, 0)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Empties the log once everything in it
+    /// is on disk in the database file
+    ///
+    fn checkpoint(&mut self) -> Result<()> {
+        self.write_back()?;
+        self.pcache.file.sync_data()?;
+        self.wal.checkpoint()
+    }
+
+    ///
+    /// Starts a transaction, none of its changes are
+    /// visible to a reopened database until it commits
+    ///
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction {
+            connection: self,
+            done: false,
+        }
+    }
+
+    ///
+    /// Inserts or replaces a single entry,
+    /// committed as its own transaction
+    ///
+    pub fn put(&mut self, key: &Key, value: &Value) -> Result<()> {
+        let mut txn = self.begin();
+        txn.put(key, value)?;
+        txn.commit()
+    }
+
+    ///
+    /// Returns the value stored under the given key,
+    /// or None if there isn't one
+    ///
+    pub fn get(&self, key: &Key) -> Result<Option<Value>> {
+        self.access.btree_get(&self.pcache, key)
+    }
+
+    ///
+    /// Returns a cursor over every entry within
+    /// the given range, in ascending key order
+    ///
+    pub fn range<R: RangeBounds<Key>>(&self, range: R) -> Result<Cursor<'_>> {
+        self.access.btree_range(&self.pcache, range)
+    }
+
+    ///
+    /// Returns a cursor over every entry whose
+    /// key starts with the given prefix
+    ///
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Cursor<'_>> {
+        let start = Bound::Included(prefix.to_vec());
+        self.range((start, prefix_end(prefix)))
+    }
+
+    ///
+    /// Removes the entry with the given key, returns
+    /// whether there was one to remove
+    ///
+    pub fn delete(&mut self, key: &Key) -> Result<bool> {
+        let mut txn = self.begin();
+        let removed = txn.delete(key)?;
+        txn.commit()?;
+
+        Ok(removed)
+    }
+
+    ///
+    /// Metadata as of the last commit
+    ///
+    pub fn header(&self) -> Header {
+        Header::from(&self.metadata)
+    }
+
+    ///
+    /// Walks the whole tree along with the free list, checking
+    /// every structural invariant and reporting all violations
+    ///
+    /// Reads through the page cache, so commits still waiting
+    /// in the write ahead log are checked without writing
+    /// anything back
+    ///
+    pub fn check(&self) -> Result<CheckReport> {
+        let mut checker = Checker {
+            io: &self.pcache,
+            n_pages: self.metadata.size / PAGE_SIZE as u64,
+            seen: HashSet::new(),
+            leaves: Vec::new(),
+            report: CheckReport::default(),
+        };
+
+        let (root, height) = (self.metadata.root, self.metadata.height);
+        if (root == 0) != (height == 0) {
+            let violation = Violation::RootHeightMismatch { root, height };
+            checker.report.violations.push(violation);
+        } else if root != 0 && checker.claim(0, root) {
+            checker.check_page(root, height - 1, 0, None, None)?;
+            checker.check_siblings();
+        }
+        checker.check_free_list(self.metadata.free_head, self.metadata.free_pages)?;
+
+        let mut report = checker.report;
+        let found = self.pcache.written_size()?;
+        if found != self.metadata.size {
+            let violation = Violation::SizeMismatch {
+                expected: self.metadata.size,
+                found,
+            };
+            report.violations.push(violation);
+        }
+
+        Ok(report)
+    }
+
+    ///
+    /// Rewrites the tree into as few pages as it fits in, moves
+    /// live pages from the tail of the database into free slots
+    /// nearer the front, then truncates the file
+    ///
+    /// Returns the number of bytes the file shrank by
+    ///
+    pub fn vacuum(&mut self) -> Result<u64> {
+        // Packing grows the file before it's truncated
+        let size = self.metadata.size;
+        let n_pages = match self.compact() {
+            Ok(n_pages) => n_pages,
+            Err(err) => {
+                self.rollback();
+                return Err(err);
+            }
+        };
+
+        self.pcache.truncate(n_pages)?;
+        info!("Vacuumed database down to {n_pages} pages");
+
+        Ok(size.saturating_sub(self.pcache.size()))
+    }
+
+    ///
+    /// Commits the packed tree and writes it back in place,
+    /// returns how many pages the file now needs
+    ///
+    fn compact(&mut self) -> Result<u64> {
+        self.access.pack(&self.pcache)?;
+        let n_pages = self.relocate_tail()?;
+        self.commit_metadata()?;
+        self.checkpoint()?;
+
+        Ok(n_pages)
+    }
+
+    ///
+    /// Packs live pages into the front of the
+    /// file, returns how many pages it now needs
+    ///
+    fn relocate_tail(&mut self) -> Result<u64> {
+        let live: HashSet<PageId> = self.access.live_pages(&self.pcache)?.into_iter().collect();
+
+        // Page 0 holds the metadata
+        let n_pages = live.len() as PageId + 1;
+        let mut slots = (1..n_pages).filter(|pid| !live.contains(pid));
+        let moves: HashMap<PageId, PageId> = live
+            .iter()
+            .filter(|&&pid| pid >= n_pages)
+            .map(|&pid| (pid, slots.next().unwrap()))
+            .collect();
+
+        self.access.relocate(&self.pcache, &moves)?;
+
+        // Every page left over is past the end now
+        let alloc = self.pcache.alloc();
+        alloc.size = n_pages as u64 * PAGE_SIZE as u64;
+        alloc.free_head = 0;
+        alloc.free_pages = 0;
+
+        Ok(n_pages as u64)
+    }
+
+    ///
+    /// Puts the tree back the way the last commit left it
+    ///
+    fn rollback(&mut self) {
+        self.pcache.discard_unlogged();
+        let alloc = self.pcache.alloc();
+        alloc.size = self.metadata.size;
+        alloc.free_head = self.metadata.free_head;
+        alloc.free_pages = self.metadata.free_pages;
+        self.access = BTree::initialize(&self.metadata);
+    }
+}
+
+fn check_key(key: &Key) -> Result<()> {
+    if key.len() > MAX_KEY_SIZE {
+        return Err(Error::KeyTooLarge {
+            len: key.len(),
+            max: MAX_KEY_SIZE,
+        });
+    }
+
+    Ok(())
+}
+
+///
+/// Group of changes applied atomically, rolled
+/// back unless committed before being dropped
+///
+pub struct Transaction<'a> {
+    connection: &'a mut Connection,
+    done: bool,
+}
+
+impl Transaction<'_> {
+    pub fn put(&mut self, key: &Key, value: &Value) -> Result<()> {
+        check_key(key)?;
+
+        let conn = &*self.connection;
+        conn.access.btree_insert(&conn.pcache, key, value)
+    }
+
+    ///
+    /// Sees changes made earlier in the transaction
+    ///
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>> {
+        self.connection.get(key)
+    }
+
+    pub fn delete(&mut self, key: &Key) -> Result<bool> {
+        let conn = &*self.connection;
+        conn.access.btree_delete(&conn.pcache, key)
+    }
+
+    ///
+    /// Logs every change made, they all
+    /// survive a crash or none of them do
+    ///
+    pub fn commit(mut self) -> Result<()> {
+        self.connection.commit_metadata()?;
+        self.done = true;
+
+        Ok(())
+    }
+
+    pub fn rollback(mut self) {
+        self.connection.rollback();
+        self.done = true;
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.connection.rollback();
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Err(err) = self.checkpoint().and_then(|_| self.wal.close()) {
+            warn!("Failed to checkpoint write ahead log: {err}");
+        }
+    }
+}
+
+///
+/// Connection shared between threads. Readers run side by
+/// side, as do puts, while every other write takes turns
+///
+/// Readers are kept apart from puts until they've committed,
+/// so a reader only ever sees committed entries, all from
+/// the same commit
+///
+#[derive(Clone)]
+pub struct Database {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    connection: RwLock<Connection>,
+    gate: Gate,
+    // Bumped by every rollback of puts, which
+    // fails any put it caught uncommitted
+    rollbacks: AtomicU64,
+    failed: AtomicBool,
+}
+
+impl Database {
+    pub fn open(db_path: &Path) -> Result<Database> {
+        Database::open_with(db_path, Options::default())
+    }
+
+    pub fn open_with(db_path: &Path, options: Options) -> Result<Database> {
+        let connection = Connection::open_with(db_path, options)?;
+        let shared = Shared {
+            connection: RwLock::new(connection),
+            gate: Gate::default(),
+            rollbacks: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+        };
+
+        Ok(Database {
+            shared: Arc::new(shared),
+        })
+    }
+
+    ///
+    /// Shared access for reads, every entry seen
+    /// belongs to the same commit
+    ///
+    /// A writer panicking mid transaction has it rolled
+    /// back, so the connection stays usable after one
+    ///
+    pub fn read(&self) -> ReadGuard<'_> {
+        let gate = GateGuard::enter(&self.shared.gate, Access::Read);
+        let connection = self
+            .shared
+            .connection
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        ReadGuard {
+            connection,
+            _gate: gate,
+        }
+    }
+
+    ///
+    /// Exclusive access for transactions and
+    /// anything else that changes the database
+    ///
+    pub fn write(&self) -> WriteGuard<'_> {
+        let gate = GateGuard::enter(&self.shared.gate, Access::Write);
+        let connection = self
+            .shared
+            .connection
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        WriteGuard {
+            connection,
+            _gate: gate,
+        }
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<Value>> {
+        self.read().get(key)
+    }
+
+    ///
+    /// Inserts or replaces a single entry alongside other
+    /// puts, latching pages rather than the whole tree
+    ///
+    /// Commits once its insert is in, along with any
+    /// others finished by then
+    ///
+    pub fn put(&self, key: &Key, value: &Value) -> Result<()> {
+        check_key(key)?;
+
+        let shared = &*self.shared;
+        let _gate = GateGuard::enter(&shared.gate, Access::Put);
+        let (rollbacks, result) = {
+            let connection = shared
+                .connection
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let rollbacks = shared.rollbacks.load(Ordering::Relaxed);
+            let result = connection
+                .access
+                .btree_insert(&connection.pcache, key, value);
+
+            // Flagged before letting go, so nothing
+            // commits the tree half changed
+            if result.is_err() {
+                shared.failed.store(true, Ordering::Relaxed);
+            }
+
+            (rollbacks, result)
+        };
+
+        result.and(self.commit_puts(rollbacks))
+    }
+
+    ///
+    /// Commits every finished put not committed yet, unless
+    /// one of them failed, in which case all are rolled back
+    ///
+    fn commit_puts(&self, rollbacks: u64) -> Result<()> {
+        let shared = &*self.shared;
+        let mut connection = shared
+            .connection
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if shared.failed.swap(false, Ordering::Relaxed) {
+            connection.rollback();
+            shared.rollbacks.fetch_add(1, Ordering::Relaxed);
+        }
+        if shared.rollbacks.load(Ordering::Relaxed) != rollbacks {
+            return Err(Error::Aborted);
+        }
+
+        // Committed already along with another put
+        if !connection.pcache.has_unlogged() {
+            return Ok(());
+        }
+
+        let result = connection.commit_metadata();
+        if result.is_err() {
+            connection.rollback();
+            shared.rollbacks.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    pub fn delete(&self, key: &Key) -> Result<bool> {
+        self.write().delete(key)
+    }
+}
+
+pub struct ReadGuard<'a> {
+    connection: RwLockReadGuard<'a, Connection>,
+    _gate: GateGuard<'a>,
+}
+
+impl Deref for ReadGuard<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+pub struct WriteGuard<'a> {
+    connection: RwLockWriteGuard<'a, Connection>,
+    _gate: GateGuard<'a>,
+}
+
+impl Deref for WriteGuard<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Put,
+    Write,
+}
+
+///
+/// Lets readers in together, or puts together, or
+/// a single writer, but never two of the three
+///
+/// Waiting writers go ahead of everyone arriving after
+/// them and waiting readers go ahead of later puts
+///
+#[derive(Default)]
+struct Gate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct GateState {
+    readers: usize,
+    puts: usize,
+    writer: bool,
+    waiting_readers: usize,
+    waiting_writers: usize,
+}
+
+impl GateState {
+    fn open(&self, access: Access) -> bool {
+        match access {
+            Access::Read => !self.writer && self.waiting_writers == 0 && self.puts == 0,
+            Access::Put => {
+                !self.writer
+                    && self.waiting_writers == 0
+                    && self.waiting_readers == 0
+                    && self.readers == 0
+            }
+            Access::Write => !self.writer && self.readers == 0 && self.puts == 0,
+        }
+    }
+
+    fn waiting(&mut self, access: Access) -> Option<&mut usize> {
+        match access {
+            Access::Read => Some(&mut self.waiting_readers),
+            Access::Write => Some(&mut self.waiting_writers),
+            Access::Put => None,
+        }
+    }
+
+    fn count(&mut self, access: Access) -> &mut usize {
+        match access {
+            Access::Read => &mut self.readers,
+            Access::Put => &mut self.puts,
+            Access::Write => unreachable!("only one writer is let in"),
+        }
+    }
+}
+
+struct GateGuard<'a> {
+    gate: &'a Gate,
+    access: Access,
+}
+
+impl GateGuard<'_> {
+    fn enter(gate: &Gate, access: Access) -> GateGuard<'_> {
+        let mut state = gate.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(waiting) = state.waiting(access) {
+            *waiting += 1;
+        }
+        while !state.open(access) {
+            state = gate
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if let Some(waiting) = state.waiting(access) {
+            *waiting -= 1;
+        }
+
+        match access {
+            Access::Write => state.writer = true,
+            _ => *state.count(access) += 1,
+        }
+
+        GateGuard { gate, access }
+    }
+}
+
+impl Drop for GateGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .gate
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match self.access {
+            Access::Write => state.writer = false,
+            access => *state.count(access) -= 1,
+        }
+
+        drop(state);
+        self.gate.changed.notify_all();
+    }
+}
+
+///
+/// Vacuums a database that isn't open anywhere
+/// else, returns the number of bytes reclaimed
+///
+pub fn vacuum(db_path: &Path, options: Options) -> Result<u64> {
+    Connection::open_with(db_path, options)?.vacuum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(keys: &[&[u8]]) -> PageData {
+        let mut page = PageData::new();
+        for key in keys {
+            assert!(page.append_item(&key.to_vec(), &b"value".to_vec()));
+        }
+
+        page
+    }
+
+    ///
+    /// Internal page over the given separators, child i is
+    /// page i + 1 and the last child has no key
+    ///
+    fn internal(separators: &[&[u8]]) -> PageData {
+        let mut page = PageData::new();
+        for (i, key) in separators.iter().chain([&&b""[..]]).enumerate() {
+            let child = (i as PageId + 1).to_be_bytes().to_vec();
+            assert!(page.append_item(&key.to_vec(), &child));
+        }
+
+        page
+    }
+
+    #[test]
+    fn search_hits() {
+        let page = leaf(&[b"b", b"d", b"f", b"h"]);
+
+        assert_eq!(page.search(b"b"), Ok(0));
+        assert_eq!(page.search(b"d"), Ok(1));
+        assert_eq!(page.search(b"f"), Ok(2));
+        assert_eq!(page.search(b"h"), Ok(3));
+    }
+
+    #[test]
+    fn search_misses() {
+        let page = leaf(&[b"b", b"d", b"f", b"h"]);
+
+        assert_eq!(page.search(b"a"), Err(0));
+        assert_eq!(page.search(b""), Err(0));
+        assert_eq!(page.search(b"c"), Err(1));
+        assert_eq!(page.search(b"dd"), Err(2));
+        assert_eq!(page.search(b"g"), Err(3));
+        assert_eq!(page.search(b"i"), Err(4));
+    }
+
+    #[test]
+    fn search_empty_page() {
+        let page = PageData::new();
+
+        assert_eq!(page.search(b""), Err(0));
+        assert_eq!(page.search(b"a"), Err(0));
+        assert_eq!(page.lower_bound(b"a", 0), 0);
+    }
+
+    #[test]
+    fn lower_bound_within_leading_items() {
+        let page = leaf(&[b"b", b"d", b"f", b"h"]);
+
+        assert_eq!(page.lower_bound(b"a", 4), 0);
+        assert_eq!(page.lower_bound(b"d", 4), 1);
+        assert_eq!(page.lower_bound(b"e", 4), 2);
+        assert_eq!(page.lower_bound(b"z", 4), 4);
+        // Items past n are never looked at
+        assert_eq!(page.lower_bound(b"h", 2), 2);
+        assert_eq!(page.lower_bound(b"b", 1), 0);
+        assert_eq!(page.lower_bound(b"c", 1), 1);
+    }
+
+    #[test]
+    fn key_at_first_and_last() {
+        let page = leaf(&[b"b", b"dd", b"fff"]);
+
+        assert_eq!(page.key_at(0), b"b");
+        assert_eq!(page.key_at(1), b"dd");
+        assert_eq!(page.key_at(2), b"fff");
+        assert_eq!(page.value_at(2), b"value");
+    }
+
+    #[test]
+    fn find_child_by_separator() {
+        let page = internal(&[b"c", b"f"]);
+
+        assert_eq!(page.key_at(2), b"");
+        // Separators are the highest key in the child to their left
+        assert_eq!(page.find_child(b""), 0);
+        assert_eq!(page.find_child(b"a"), 0);
+        assert_eq!(page.find_child(b"c"), 0);
+        assert_eq!(page.find_child(b"d"), 1);
+        assert_eq!(page.find_child(b"f"), 1);
+        assert_eq!(page.get_child(page.find_child(b"f")), 2);
+    }
+
+    #[test]
+    fn find_child_past_last_separator() {
+        let page = internal(&[b"c", b"f"]);
+
+        // The keyless rightmost pointer takes everything past f,
+        // even though an empty key sorts before every other
+        assert_eq!(page.find_child(b"g"), 2);
+        assert_eq!(page.find_child(b"\xff\xff"), 2);
+        assert_eq!(page.get_child(page.find_child(b"g")), 3);
+
+        let page = internal(&[]);
+        assert_eq!(page.find_child(b"a"), 0);
+    }
+
+    fn check_free(io: &PageCache, pid: PageId, height: u16) {
+        let page = io.get_page(pid).unwrap();
+        let used: usize = page.get_items().iter().map(item_size).sum();
+        assert_eq!(page.get_free(), PAGE_SIZE - PAGE_HEADER_SIZE - used);
+
+        if height > 0 {
+            for ip in 0..page.get_n_items() {
+                check_free(io, page.get_child(ip), height - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn free_counter_matches_items() {
+        let path = Path::new("free-counter");
+        let _ = std::fs::remove_file(path);
+        let mut connection = Connection::open(path).unwrap();
+        let key = |i: usize| format!("key {:05}", i * 7919 % 3000).into_bytes();
+        let value = |i: usize| vec![b'v'; i % 300];
+
+        // Enough to split leaves and grow a level of internal pages
+        for i in 0..3000 {
+            connection.put(&key(i), &value(i)).unwrap();
+        }
+        let height = connection.access.height();
+        assert!(height > 1);
+        check_free(&connection.pcache, connection.access.root(), height - 1);
+
+        // Replacing with different lengths and removing
+        // most keys again leaves pages to merge
+        for i in 0..3000 {
+            match i % 10 {
+                0 => connection.put(&key(i), &value(i + 150)).unwrap(),
+                _ => assert!(connection.delete(&key(i)).unwrap()),
+            }
+        }
+        let height = connection.access.height();
+        check_free(&connection.pcache, connection.access.root(), height - 1);
+
+        // Down to a single leaf once the root collapses
+        for i in (0..3000).step_by(10).filter(|i| i % 500 != 0) {
+            assert!(connection.delete(&key(i)).unwrap());
+        }
+        assert_eq!(connection.access.height(), 1);
+        check_free(&connection.pcache, connection.access.root(), 0);
+
+        drop(connection);
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(Wal::path_for(path));
     }
 }
@@ -0,0 +1,107 @@
+use crate::error::{Error, Result};
+use crate::store::{PageCache, PageId, PAGE_SIZE};
+
+///
+/// Version 1 pages only have the item count in their header,
+/// followed by 4 unused bytes, then the offsets array
+///
+const V1_HEADER_SIZE: usize = 6;
+
+///
+/// Calls f with every entry of a version 1 tree, walking
+/// down through child pointers as leaves weren't linked
+///
+/// Values are stored as is, without a tag or overflow
+/// pages. Pages are read straight from disk, none carry
+/// a checksum
+///
+pub(crate) fn for_each_entry<F>(
+    io: &mut PageCache,
+    root: PageId,
+    height: u16,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&mut PageCache, Vec<u8>, Vec<u8>) -> Result<()>,
+{
+    if root == 0 {
+        return Ok(());
+    }
+
+    walk(io, root, height - 1, &mut f)
+}
+
+fn walk<F>(io: &mut PageCache, pid: PageId, height: u16, f: &mut F) -> Result<()>
+where
+    F: FnMut(&mut PageCache, Vec<u8>, Vec<u8>) -> Result<()>,
+{
+    let page = io.read_raw(pid)?;
+    for ip in 0..n_items(&page) {
+        let (key, value) = item(&page, ip, pid)?;
+        if height == 0 {
+            f(io, key.to_vec(), value.to_vec())?;
+            continue;
+        }
+
+        let child = match value.try_into() {
+            Ok(child) => PageId::from_be_bytes(child),
+            Err(_) => return Err(Error::Corrupt { page: pid }),
+        };
+        walk(io, child, height - 1, f)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn n_items(page: &[u8]) -> usize {
+    get_u16(page, 0) as usize
+}
+
+///
+/// Space between the offsets array and the lowest item,
+/// version 1 pages don't keep track of it themselves
+///
+pub(crate) fn free(page: &[u8]) -> usize {
+    let n_items = n_items(page);
+    let items_start = V1_HEADER_SIZE + n_items * 2;
+    if items_start > PAGE_SIZE {
+        return 0;
+    }
+
+    let lowest = (0..n_items)
+        .map(|ip| get_u16(page, V1_HEADER_SIZE + ip * 2) as usize)
+        .min()
+        .unwrap_or(PAGE_SIZE);
+
+    lowest.saturating_sub(items_start)
+}
+
+///
+/// Splits an item into its key and value,
+/// checking every offset stays inside the page
+///
+pub(crate) fn item(page: &[u8], ip: usize, pid: PageId) -> Result<(&[u8], &[u8])> {
+    let corrupt = || Error::Corrupt { page: pid };
+    let slot = V1_HEADER_SIZE + ip * 2;
+    if slot + 2 > PAGE_SIZE {
+        return Err(corrupt());
+    }
+
+    let offs = get_u16(page, slot) as usize;
+    if offs + 4 > PAGE_SIZE {
+        return Err(corrupt());
+    }
+
+    let kl = get_u16(page, offs) as usize;
+    let vl = get_u16(page, offs + 2) as usize;
+    let key = page.get(offs + 4..offs + 4 + kl).ok_or_else(corrupt)?;
+    let value = page
+        .get(offs + 4 + kl..offs + 4 + kl + vl)
+        .ok_or_else(corrupt)?;
+
+    Ok((key, value))
+}
+
+fn get_u16(page: &[u8], offs: usize) -> u16 {
+    u16::from_be_bytes(page[offs..offs + 2].try_into().unwrap())
+}
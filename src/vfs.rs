@@ -0,0 +1,85 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+///
+/// File system the database and its write ahead
+/// log live on, swapped out to inject faults in tests
+///
+pub trait Vfs: Debug + Send + Sync {
+    ///
+    /// Opens a file for reading and writing, creating it
+    /// if asked to, fails with NotFound otherwise
+    ///
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+}
+
+///
+/// Positioned I/O on a single open file
+///
+pub trait VfsFile: Send + Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    ///
+    /// Length of the file in bytes
+    ///
+    fn size(&self) -> io::Result<u64>;
+
+    ///
+    /// Returns once every write so far is on disk
+    ///
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+///
+/// Files on the local file system
+///
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsVfs;
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        let file = File::options()
+            .create(create)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        Ok(Box::new(file))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+}
+
+impl VfsFile for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        FileExt::write_all_at(self, buf, offset)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
@@ -0,0 +1,191 @@
+use crate::crc::crc32c;
+use crate::error::Result;
+use crate::store::{PageId, PAGE_SIZE};
+use crate::vfs::{Vfs, VfsFile};
+use log::info;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+///
+/// Marks a commit record, page ids never reach it
+///
+const COMMIT_MARKER: u32 = u32::MAX;
+const FRAME_SIZE: usize = 4 + PAGE_SIZE;
+
+///
+/// Write ahead log, every commit appends images of the
+/// pages it changed before any of them are written in place
+///
+/// Records:
+///
+/// (1) page frame: page id (u32) | page image
+/// (2) commit: marker (u32) | # frames (u32) | header len (u32) | header | crc32c (u32)
+///
+/// The header is written at the very start of the database
+/// file, the checksum covers the frames and commit record
+///
+/// Frames not followed by a matching commit record
+/// were torn by a crash and are ignored on replay
+///
+pub(crate) struct Wal {
+    vfs: Arc<dyn Vfs>,
+    file: Box<dyn VfsFile>,
+    path: PathBuf,
+    len: u64,
+    synced: bool,
+}
+
+impl Wal {
+    ///
+    /// Log file lives next to the database,
+    /// with "-wal" appended to its name
+    ///
+    pub fn path_for(db_path: &Path) -> PathBuf {
+        let mut path = OsString::from(db_path.as_os_str());
+        path.push("-wal");
+        PathBuf::from(path)
+    }
+
+    ///
+    /// Starts an empty log, replacing any left behind
+    ///
+    pub fn create(vfs: Arc<dyn Vfs>, path: PathBuf) -> Result<Wal> {
+        let file = vfs.open(&path, true)?;
+        file.set_len(0)?;
+
+        Ok(Wal {
+            vfs,
+            file,
+            path,
+            len: 0,
+            synced: true,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    ///
+    /// Appends one committed batch of pages, it only
+    /// survives a crash once the log is synced
+    ///
+    pub fn append(&mut self, pages: &[(PageId, &[u8])], header: &[u8]) -> Result<()> {
+        let mut buffer = Vec::with_capacity(pages.len() * FRAME_SIZE + header.len() + 16);
+        for (pid, page) in pages {
+            buffer.extend_from_slice(&pid.to_be_bytes());
+            buffer.extend_from_slice(page);
+        }
+
+        buffer.extend_from_slice(&COMMIT_MARKER.to_be_bytes());
+        buffer.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(header);
+        let crc = crc32c(&buffer);
+        buffer.extend_from_slice(&crc.to_be_bytes());
+
+        self.file.write_all_at(&buffer, self.len)?;
+        self.len += buffer.len() as u64;
+        self.synced = false;
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        if !self.synced {
+            self.file.sync_data()?;
+            self.synced = true;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Writes every committed page image left in the log
+    /// back into the database file, then deletes the log
+    ///
+    pub fn recover(vfs: &dyn Vfs, path: &Path, db: &dyn VfsFile) -> Result<()> {
+        let file = match vfs.open(path, false) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut log = vec![0u8; file.size()? as usize];
+        file.read_exact_at(&mut log, 0)?;
+
+        let mut commits = 0;
+        let mut batch = 0; // start of the frames awaiting a commit record
+        let mut pos = 0;
+        while pos + 4 <= log.len() {
+            if read_u32(&log, pos) != COMMIT_MARKER {
+                if pos + FRAME_SIZE > log.len() {
+                    break;
+                }
+                pos += FRAME_SIZE;
+                continue;
+            }
+
+            if pos + 12 > log.len() {
+                break;
+            }
+            let n_frames = read_u32(&log, pos + 4) as usize;
+            let header_len = read_u32(&log, pos + 8) as usize;
+            let end = pos + 12 + header_len;
+            if end + 4 > log.len()
+                || pos - batch != n_frames * FRAME_SIZE
+                || crc32c(&log[batch..end]) != read_u32(&log, end)
+            {
+                break;
+            }
+
+            for frame in log[batch..pos].chunks_exact(FRAME_SIZE) {
+                let pid = read_u32(frame, 0);
+                db.write_all_at(&frame[4..], pid as u64 * PAGE_SIZE as u64)?;
+            }
+            db.write_all_at(&log[pos + 12..end], 0)?;
+
+            commits += 1;
+            pos = end + 4;
+            batch = pos;
+        }
+
+        info!("Replayed {commits} commits from write ahead log");
+
+        db.sync_data()?;
+        vfs.remove(path)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Empties the log, only once the database file
+    /// holds every logged page on disk
+    ///
+    /// Truncation is synced so stale commits can
+    /// never resurface behind newer ones
+    ///
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.len = 0;
+        self.synced = true;
+
+        Ok(())
+    }
+
+    ///
+    /// Deletes the log once checkpointed, done
+    /// when a connection is closed cleanly
+    ///
+    pub fn close(&mut self) -> Result<()> {
+        self.vfs.remove(&self.path)?;
+
+        Ok(())
+    }
+}
+
+fn read_u32(bytes: &[u8], offs: usize) -> u32 {
+    u32::from_be_bytes(bytes[offs..offs + 4].try_into().unwrap())
+}
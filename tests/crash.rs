@@ -0,0 +1,380 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tinystore::store::{Connection, Durability, Options};
+use tinystore::vfs::{Vfs, VfsFile};
+
+///
+/// What happens to the write a crash lands on
+///
+#[derive(Debug, Clone, Copy)]
+enum Fault {
+    /// Never reaches the file
+    Drop,
+    /// Only its first half reaches the file
+    Tear,
+    /// Lands, but the file keeps its old length
+    Truncate,
+}
+
+#[derive(Clone)]
+enum Op {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+///
+/// Synced contents along with every write since,
+/// so losing power can drop any of them
+///
+#[derive(Default)]
+struct FileState {
+    synced: Vec<u8>,
+    pending: Vec<Op>,
+}
+
+impl FileState {
+    fn contents(&self) -> Vec<u8> {
+        let mut data = self.synced.clone();
+        for op in &self.pending {
+            apply(&mut data, op);
+        }
+
+        data
+    }
+}
+
+fn apply(data: &mut Vec<u8>, op: &Op) {
+    match op {
+        Op::Write(offset, bytes) => {
+            let offset = *offset as usize;
+            if data.len() < offset + bytes.len() {
+                data.resize(offset + bytes.len(), 0);
+            }
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        Op::SetLen(len) => data.resize(*len as usize, 0),
+    }
+}
+
+#[derive(Default)]
+struct Disk {
+    files: HashMap<PathBuf, FileState>,
+    ios: usize,
+    fault: Option<(usize, Fault)>,
+    crashed: bool,
+}
+
+impl Disk {
+    ///
+    /// Counts a write or sync, failing it and
+    /// everything after once the crash point is hit
+    ///
+    fn io(&mut self, path: &Path, op: Option<Op>) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("crashed"));
+        }
+
+        let n = self.ios;
+        self.ios += 1;
+
+        let file = self.files.get_mut(path).unwrap();
+        match (self.fault, op) {
+            (Some((at, fault)), op) if at == n => {
+                self.crashed = true;
+                match (fault, op) {
+                    (Fault::Tear, Some(Op::Write(offset, mut bytes))) => {
+                        bytes.truncate(bytes.len() / 2);
+                        file.pending.push(Op::Write(offset, bytes));
+                    }
+                    (Fault::Truncate, Some(op)) => {
+                        let len = file.contents().len() as u64;
+                        file.pending.push(op);
+                        file.pending.push(Op::SetLen(len));
+                    }
+                    _ => {}
+                }
+                Err(io::Error::other("crashed"))
+            }
+            (_, Some(op)) => {
+                file.pending.push(op);
+                Ok(())
+            }
+            (_, None) => {
+                file.synced = file.contents();
+                file.pending.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+///
+/// In memory file system that crashes at the
+/// Nth write or sync it is asked to do
+///
+#[derive(Clone, Default)]
+struct FaultVfs {
+    disk: Arc<Mutex<Disk>>,
+}
+
+impl fmt::Debug for FaultVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FaultVfs")
+    }
+}
+
+impl FaultVfs {
+    fn crash_at(n: usize, fault: Fault) -> FaultVfs {
+        let vfs = FaultVfs::default();
+        vfs.disk.lock().unwrap().fault = Some((n, fault));
+        vfs
+    }
+
+    fn ios(&self) -> usize {
+        self.disk.lock().unwrap().ios
+    }
+
+    ///
+    /// Restarts after the crash, on power loss only a
+    /// random subset of the unsynced writes survive
+    ///
+    fn restart(&self, power_loss: bool, rng: &mut StdRng) {
+        let mut disk = self.disk.lock().unwrap();
+        for file in disk.files.values_mut() {
+            let mut data = file.synced.clone();
+            for op in &file.pending {
+                if !power_loss || rng.random_bool(0.5) {
+                    apply(&mut data, op);
+                }
+            }
+            file.synced = data;
+            file.pending.clear();
+        }
+
+        disk.fault = None;
+        disk.crashed = false;
+    }
+}
+
+impl Vfs for FaultVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        let mut disk = self.disk.lock().unwrap();
+        if disk.crashed {
+            return Err(io::Error::other("crashed"));
+        }
+        if !disk.files.contains_key(path) {
+            if !create {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            disk.files.insert(path.to_path_buf(), FileState::default());
+        }
+
+        Ok(Box::new(FaultFile {
+            disk: self.disk.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut disk = self.disk.lock().unwrap();
+        if disk.crashed {
+            return Err(io::Error::other("crashed"));
+        }
+        disk.files.remove(path);
+
+        Ok(())
+    }
+}
+
+struct FaultFile {
+    disk: Arc<Mutex<Disk>>,
+    path: PathBuf,
+}
+
+impl VfsFile for FaultFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let disk = self.disk.lock().unwrap();
+        let data = disk.files[&self.path].contents();
+        let offset = offset as usize;
+        if offset + buf.len() > data.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let op = Op::Write(offset, buf.to_vec());
+        self.disk.lock().unwrap().io(&self.path, Some(op))
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.disk.lock().unwrap().io(&self.path, Some(Op::SetLen(len)))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let disk = self.disk.lock().unwrap();
+        Ok(disk.files[&self.path].contents().len() as u64)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.disk.lock().unwrap().io(&self.path, None)
+    }
+}
+
+enum Step {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Reopen,
+    Vacuum,
+}
+
+fn generate_workload(n_steps: usize) -> Vec<Step> {
+    let mut rng = StdRng::seed_from_u64(14);
+
+    (0..n_steps)
+        .map(|i| {
+            let key = format!("key{:03}", rng.random_range(0..80)).into_bytes();
+            if i == n_steps / 2 {
+                Step::Reopen
+            } else if i % 20 == 19 {
+                Step::Vacuum
+            } else if rng.random_range(0..5) == 0 {
+                Step::Delete(key)
+            } else {
+                // Now and then large enough to overflow
+                let len = if rng.random_range(0..10) == 0 { 5000 } else { 20 };
+                Step::Put(key, vec![rng.random(); len])
+            }
+        })
+        .collect()
+}
+
+fn options(vfs: &FaultVfs) -> Options {
+    Options {
+        cache_capacity: 4,
+        durability: Durability::OnCommit,
+        vfs: Arc::new(vfs.clone()),
+    }
+}
+
+///
+/// Runs the workload until an I/O error, recording what was
+/// acknowledged and returning the step that was in flight
+///
+/// Past the last step is returned for crashes while closing
+///
+fn run_workload(
+    vfs: &FaultVfs,
+    workload: &[Step],
+    acked: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+) -> usize {
+    let path = Path::new("crash");
+    let Ok(mut connection) = Connection::open_with(path, options(vfs)) else {
+        return 0;
+    };
+
+    for (i, step) in workload.iter().enumerate() {
+        let result = match step {
+            Step::Put(key, value) => connection.put(key, value).map(|_| {
+                acked.insert(key.clone(), value.clone());
+            }),
+            Step::Delete(key) => connection.delete(key).map(|_| {
+                acked.remove(key);
+            }),
+            Step::Vacuum => connection.vacuum().map(|_| ()),
+            Step::Reopen => {
+                drop(connection);
+                match Connection::open_with(path, options(vfs)) {
+                    Ok(reopened) => {
+                        connection = reopened;
+                        Ok(())
+                    }
+                    Err(_) => return i,
+                }
+            }
+        };
+
+        if result.is_err() {
+            return i;
+        }
+    }
+
+    workload.len()
+}
+
+///
+/// Reopens after a crash and checks the tree is intact and
+/// holds every acknowledged write, allowing the in flight
+/// step to have landed or not
+///
+fn verify(vfs: &FaultVfs, workload: &[Step], acked: &BTreeMap<Vec<u8>, Vec<u8>>, step: usize) {
+    let connection = Connection::open_with(Path::new("crash"), options(vfs))
+        .unwrap_or_else(|err| panic!("reopen failed after crash in step {step}: {err}"));
+
+    let report = connection.check().unwrap();
+    assert!(
+        report.violations.is_empty(),
+        "step {step}: {:?}",
+        report.violations
+    );
+
+    let entries: Vec<_> = connection.range(..).unwrap().map(|e| e.unwrap()).collect();
+    let mut reversed: Vec<_> = connection
+        .range(..)
+        .unwrap()
+        .rev()
+        .map(|e| e.unwrap())
+        .collect();
+    reversed.reverse();
+    assert_eq!(entries, reversed, "step {step}");
+    assert!(entries.windows(2).all(|w| w[0].0 < w[1].0), "step {step}");
+
+    let mut landed = acked.clone();
+    match workload.get(step) {
+        Some(Step::Put(key, value)) => {
+            landed.insert(key.clone(), value.clone());
+        }
+        Some(Step::Delete(key)) => {
+            landed.remove(key);
+        }
+        Some(Step::Reopen | Step::Vacuum) | None => {}
+    }
+
+    let found: BTreeMap<_, _> = entries.into_iter().collect();
+    assert!(found == *acked || found == landed, "step {step}");
+    for (key, value) in &found {
+        assert_eq!(connection.get(key).unwrap().as_ref(), Some(value), "step {step}");
+    }
+}
+
+#[test]
+fn crash_at_every_write() {
+    let _ = env_logger::try_init();
+
+    let workload = generate_workload(60);
+
+    let clean = FaultVfs::default();
+    run_workload(&clean, &workload, &mut BTreeMap::new());
+    let n_ios = clean.ios();
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for n in 0..n_ios {
+        for fault in [Fault::Drop, Fault::Tear, Fault::Truncate] {
+            for power_loss in [false, true] {
+                let vfs = FaultVfs::crash_at(n, fault);
+                let mut acked = BTreeMap::new();
+                let step = run_workload(&vfs, &workload, &mut acked);
+
+                vfs.restart(power_loss, &mut rng);
+                verify(&vfs, &workload, &acked, step);
+            }
+        }
+    }
+}
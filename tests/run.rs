@@ -1,12 +1,20 @@
-use anyhow::Result;
 use log::info;
 use rand::distr::{Alphanumeric, SampleString};
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tinystore::store::Connection;
+use tinystore::store::{
+    vacuum, Connection, Database, Durability, Options, Violation, MAX_KEY_SIZE,
+};
+use tinystore::vfs::{OsVfs, Vfs};
+use tinystore::Error;
 
 
+#[allow(unused_variables, clippy::unnecessary_get_then_check)]
 fn generate_entries(
     n_entries: usize,
     key_len: usize,
@@ -90,11 +98,12 @@ fn insert_items(connection: &mut Connection, items: &HashMap<Vec<u8>, Vec<u8>>)
     now.elapsed()
 }
 
+#[allow(unused_variables, clippy::needless_borrow)]
 fn get_items(connection: &mut Connection, items: &HashMap<Vec<u8>, Vec<u8>>) -> (usize, Duration) {
     let now = Instant::now();
     let mut successful: usize = 0;
     for (i, (key, value)) in items.iter().enumerate() {
-        if let Ok(rvalue) = connection.get(&key) {
+        if let Ok(Some(rvalue)) = connection.get(&key) {
             assert_eq!(rvalue, *value);
             successful += 1;
         }
@@ -103,9 +112,15 @@ fn get_items(connection: &mut Connection, items: &HashMap<Vec<u8>, Vec<u8>>) ->
     (successful, now.elapsed())
 }
 
+fn check_tree(connection: &mut Connection) {
+    let report = connection.check().unwrap();
+    assert!(report.is_ok(), "{:?}", report.violations);
+}
+
 // TODO: make multiple tests
 // TODO: understand iterators? Sequential insert / get
 #[test]
+#[allow(non_upper_case_globals, unused_must_use, clippy::needless_borrow)]
 fn fill_and_query() {
     env_logger::try_init();
     const n: usize = 10000;
@@ -119,6 +134,7 @@ fn fill_and_query() {
 
     let insertion_elapsed = insert_items(&mut connection, &items);
     let (successful, query_elapsed) = get_items(&mut connection, &items);
+    check_tree(&mut connection);
 
     std::fs::remove_file("test1");
 
@@ -133,6 +149,12 @@ fn fill_and_query() {
 }
 
 #[test]
+#[allow(
+    non_upper_case_globals,
+    unused_must_use,
+    unused_variables,
+    clippy::needless_borrow
+)]
 fn multiple_open_and_fill() {
     env_logger::try_init();
     const times: usize = 8;
@@ -149,6 +171,7 @@ fn multiple_open_and_fill() {
         let mut connection = Connection::open(&path).unwrap();
         let insertion_elapsed = insert_items(&mut connection, &items);
         let (successful, query_elapsed) = get_items(&mut connection, &items);
+        check_tree(&mut connection);
 
         total_lost += n - successful;
         total_time += insertion_elapsed + query_elapsed;
@@ -168,3 +191,1443 @@ fn multiple_open_and_fill() {
     info!("Total lost: {}", total_lost);
     info!("Took:\t{}s\t{}ms", total_time.as_secs(), total_time.as_millis());
 }
+
+#[test]
+fn fill_and_delete() {
+    let _ = env_logger::try_init();
+    const N: usize = 20000;
+    const KL: usize = 10;
+    const VL: usize = 6;
+
+    let items = generate_entries(N, KL, VL);
+    let (kept, removed): (HashMap<_, _>, HashMap<_, _>) =
+        items.into_iter().partition(|(key, _)| key[0] % 4 == 0);
+
+    let path = Path::new("test3");
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &removed);
+    insert_items(&mut connection, &kept);
+
+    for key in removed.keys() {
+        assert!(connection.delete(key).unwrap());
+    }
+    assert!(!connection.delete(&b"missing".to_vec()).unwrap());
+    check_tree(&mut connection);
+
+    // Reopen to make sure the shrunken tree was persisted
+    drop(connection);
+    let mut connection = Connection::open(path).unwrap();
+
+    for key in removed.keys() {
+        assert!(connection.get(key).unwrap().is_none());
+    }
+    let (successful, _) = get_items(&mut connection, &kept);
+
+    // Emptying the tree entirely should leave it usable
+    for key in kept.keys() {
+        assert!(connection.delete(key).unwrap());
+    }
+    connection.put(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+    assert_eq!(
+        connection.get(&b"key".to_vec()).unwrap(),
+        Some(b"value".to_vec())
+    );
+    check_tree(&mut connection);
+
+    std::fs::remove_file("test3").unwrap();
+
+    assert_eq!(successful, kept.len());
+}
+
+#[test]
+fn range_scan() {
+    let _ = env_logger::try_init();
+    const N: usize = 20000;
+    const KL: usize = 10;
+    const VL: usize = 6;
+
+    let items = generate_entries(N, KL, VL);
+    let sorted: BTreeMap<Vec<u8>, Vec<u8>> = items.clone().into_iter().collect();
+
+    let path = Path::new("test4");
+    let mut connection = Connection::open(path).unwrap();
+    assert_eq!(connection.range(..).unwrap().count(), 0);
+    insert_items(&mut connection, &items);
+
+    let start = b"D".to_vec();
+    let end = b"k".to_vec();
+    let scanned: Vec<_> = connection
+        .range(start.clone()..end.clone())
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let expected: Vec<_> = sorted
+        .range(start.clone()..end.clone())
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    assert_eq!(scanned, expected);
+
+    // Bounds on existing keys
+    let keys: Vec<_> = sorted.keys().cloned().collect();
+    let (lo, hi) = (keys[100].clone(), keys[N - 100].clone());
+    let bounds = (Bound::Excluded(lo.clone()), Bound::Included(hi.clone()));
+    let scanned: Vec<_> = connection
+        .range(bounds.clone())
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(scanned, keys[101..=N - 100]);
+
+    let scanned = connection.range(..=lo).unwrap().count();
+    assert_eq!(scanned, 101);
+    let scanned = connection.range(hi..).unwrap().count();
+    assert_eq!(scanned, 100);
+    let scanned = connection.range(..).unwrap().count();
+    assert_eq!(scanned, N);
+
+    std::fs::remove_file("test4").unwrap();
+}
+
+#[test]
+fn reverse_scan() {
+    let _ = env_logger::try_init();
+    const N: usize = 20000;
+    const KL: usize = 10;
+    const VL: usize = 6;
+
+    let items = generate_entries(N, KL, VL);
+    let keys: Vec<Vec<u8>> = {
+        let mut keys: Vec<_> = items.keys().cloned().collect();
+        keys.sort();
+        keys
+    };
+
+    let path = Path::new("test5");
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &items);
+
+    // Latest N keys
+    let latest: Vec<_> = connection
+        .range(..)
+        .unwrap()
+        .rev()
+        .take(10)
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<_> = keys.iter().rev().take(10).cloned().collect();
+    assert_eq!(latest, expected);
+
+    let (lo, hi) = (keys[500].clone(), keys[N - 500].clone());
+    let scanned: Vec<_> = connection
+        .range(lo.clone()..=hi.clone())
+        .unwrap()
+        .rev()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<_> = keys[500..=N - 500].iter().rev().cloned().collect();
+    assert_eq!(scanned, expected);
+
+    // Both ends meet in the middle without overlapping
+    let mut cursor = connection.range(lo..hi).unwrap();
+    let mut seen = Vec::new();
+    while let Some(entry) = cursor.next() {
+        seen.push(entry.unwrap().0);
+        if let Some(entry) = cursor.next_back() {
+            seen.push(entry.unwrap().0);
+        }
+    }
+    seen.sort();
+    assert_eq!(seen, keys[500..N - 500]);
+
+    // Stepping back over entries returns them again
+    let mut cursor = connection.range(..).unwrap();
+    for _ in 0..300 {
+        cursor.next().unwrap().unwrap();
+    }
+    for i in (0..300).rev() {
+        assert_eq!(cursor.prev().unwrap().unwrap().0, keys[i]);
+    }
+    assert!(cursor.prev().is_none());
+    assert_eq!(cursor.next().unwrap().unwrap().0, keys[0]);
+
+    std::fs::remove_file("test5").unwrap();
+}
+
+#[test]
+fn prefix_scan() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test6");
+    let mut connection = Connection::open(path).unwrap();
+
+    let mut namespaced = Vec::new();
+    for user in 0..200 {
+        for field in ["email", "name", "tag"] {
+            let key = format!("user/{user}/{field}").into_bytes();
+            connection.put(&key, &b"value".to_vec()).unwrap();
+            namespaced.push(key);
+        }
+    }
+    connection.put(&b"user0".to_vec(), &b"value".to_vec()).unwrap();
+    connection.put(&vec![0xff, 0xff], &b"value".to_vec()).unwrap();
+    namespaced.sort();
+
+    let scanned: Vec<_> = connection
+        .scan_prefix(b"user/12/")
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(
+        scanned,
+        vec![
+            b"user/12/email".to_vec(),
+            b"user/12/name".to_vec(),
+            b"user/12/tag".to_vec()
+        ]
+    );
+
+    let scanned: Vec<_> = connection
+        .scan_prefix(b"user/")
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(scanned, namespaced);
+
+    assert_eq!(connection.scan_prefix(b"nobody/").unwrap().count(), 0);
+    assert_eq!(connection.scan_prefix(&[0xff]).unwrap().count(), 1);
+    assert_eq!(connection.scan_prefix(b"").unwrap().count(), 602);
+
+    std::fs::remove_file("test6").unwrap();
+}
+
+#[test]
+fn large_values() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test7");
+    let mut connection = Connection::open(path).unwrap();
+
+    let mut items = generate_entries(2000, 10, 6);
+    for (i, len) in [1000, 4096, 10_000, 1 << 20, 5 << 20].into_iter().enumerate() {
+        let value: Vec<u8> = (0..len).map(|b| (b % 251) as u8).collect();
+        items.insert(format!("large{i}").into_bytes(), value);
+    }
+    insert_items(&mut connection, &items);
+
+    drop(connection);
+    let mut connection = Connection::open(path).unwrap();
+    let (successful, _) = get_items(&mut connection, &items);
+    assert_eq!(successful, items.len());
+
+    let scanned: Vec<_> = connection
+        .scan_prefix(b"large")
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    assert_eq!(scanned.len(), 5);
+    for (key, value) in scanned {
+        assert_eq!(value, items[&key]);
+    }
+
+    // Overwriting shrinks back to an inline value
+    let key = b"large4".to_vec();
+    connection.put(&key, &b"small".to_vec()).unwrap();
+    assert_eq!(connection.get(&key).unwrap(), Some(b"small".to_vec()));
+    check_tree(&mut connection);
+
+    std::fs::remove_file("test7").unwrap();
+}
+
+#[test]
+fn key_size_limit() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test8");
+    let mut connection = Connection::open(path).unwrap();
+
+    // Max size keys with values that both fit and overflow
+    let items: HashMap<Vec<u8>, Vec<u8>> = (0..200u32)
+        .map(|i| {
+            let mut key = vec![b'k'; MAX_KEY_SIZE];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            (key, vec![b'v'; (i as usize % 4) * 400])
+        })
+        .collect();
+    insert_items(&mut connection, &items);
+
+    let key = vec![b'k'; MAX_KEY_SIZE + 1];
+    let err = connection.put(&key, &b"value".to_vec()).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::KeyTooLarge { len, max: MAX_KEY_SIZE } if len == MAX_KEY_SIZE + 1
+    ));
+
+    let (successful, _) = get_items(&mut connection, &items);
+    assert_eq!(successful, items.len());
+    assert!(connection.get(&key).unwrap().is_none());
+
+    std::fs::remove_file("test8").unwrap();
+}
+
+#[test]
+fn foreign_file() {
+    let _ = env_logger::try_init();
+
+    std::fs::write("test9", vec![0u8; 4096]).unwrap();
+    let err = Connection::open(Path::new("test9")).err().unwrap();
+    std::fs::remove_file("test9").unwrap();
+
+    assert!(matches!(err, Error::BadMagic { found: 0 }));
+}
+
+#[test]
+fn small_cache() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test10");
+    let options = Options {
+        cache_capacity: 2,
+        ..Options::default()
+    };
+    let items = generate_entries(2000, 16, 64);
+
+    let mut connection = Connection::open_with(path, options.clone()).unwrap();
+    insert_items(&mut connection, &items);
+    let (successful, _) = get_items(&mut connection, &items);
+    assert_eq!(successful, items.len());
+
+    let mut removed = HashMap::new();
+    for (key, value) in items.iter().step_by(2) {
+        assert!(connection.delete(key).unwrap());
+        removed.insert(key.clone(), value.clone());
+    }
+    drop(connection);
+
+    let mut connection = Connection::open_with(path, options).unwrap();
+    let (successful, _) = get_items(&mut connection, &items);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(successful, items.len() - removed.len());
+    for key in removed.keys() {
+        assert!(connection.get(key).unwrap().is_none());
+    }
+}
+
+#[test]
+fn wal_replay() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test11");
+    let items = generate_entries(200, 10, 6);
+
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &items);
+    // Crash without closing, leaving the log behind
+    std::mem::forget(connection);
+
+    // Lose every in place write, along with a torn trailing commit
+    let size = std::fs::metadata(path).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+    file.set_len(0).unwrap();
+    file.set_len(size).unwrap();
+    let mut wal = std::fs::OpenOptions::new()
+        .append(true)
+        .open("test11-wal")
+        .unwrap();
+    wal.write_all(&[7u8; 100]).unwrap();
+
+    let mut connection = Connection::open(path).unwrap();
+    let (successful, _) = get_items(&mut connection, &items);
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(successful, items.len());
+    assert!(!Path::new("test11-wal").exists());
+}
+
+#[test]
+fn transactions() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test12");
+    let items = generate_entries(2000, 10, 6);
+    let extra = generate_entries(2000, 12, 6);
+
+    let mut connection = Connection::open(path).unwrap();
+    let mut txn = connection.begin();
+    for (key, value) in &items {
+        txn.put(key, value).unwrap();
+    }
+    let (key, value) = items.iter().next().unwrap();
+    assert_eq!(txn.get(key).unwrap().as_ref(), Some(value));
+    txn.commit().unwrap();
+
+    // Enough changes to split pages, then thrown away
+    let mut txn = connection.begin();
+    for (key, value) in &extra {
+        txn.put(key, value).unwrap();
+    }
+    for key in items.keys().step_by(2) {
+        assert!(txn.delete(key).unwrap());
+    }
+    txn.rollback();
+
+    {
+        let mut txn = connection.begin();
+        for (key, value) in &extra {
+            txn.put(key, value).unwrap();
+        }
+    }
+
+    let mut expected: Vec<_> = items.clone().into_iter().collect();
+    expected.sort();
+    let entries: Vec<_> = connection.range(..).unwrap().map(|e| e.unwrap()).collect();
+    assert_eq!(entries, expected);
+
+    drop(connection);
+    let connection = Connection::open(path).unwrap();
+    let entries: Vec<_> = connection.range(..).unwrap().map(|e| e.unwrap()).collect();
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(entries, expected);
+}
+
+///
+/// Files on the local file system, counting
+/// how often each of them is synced
+///
+#[derive(Debug, Default)]
+struct SyncCounter {
+    syncs: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+impl SyncCounter {
+    fn syncs(&self, path: &Path) -> usize {
+        self.syncs.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+}
+
+impl Vfs for SyncCounter {
+    fn open(&self, path: &Path, create: bool) -> std::io::Result<Box<dyn tinystore::vfs::VfsFile>> {
+        Ok(Box::new(CountedFile {
+            file: OsVfs.open(path, create)?,
+            path: path.to_path_buf(),
+            syncs: self.syncs.clone(),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        OsVfs.remove(path)
+    }
+}
+
+struct CountedFile {
+    file: Box<dyn tinystore::vfs::VfsFile>,
+    path: PathBuf,
+    syncs: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+impl tinystore::vfs::VfsFile for CountedFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        self.file.size()
+    }
+
+    fn sync_data(&self) -> std::io::Result<()> {
+        let mut syncs = self.syncs.lock().unwrap();
+        *syncs.entry(self.path.clone()).or_default() += 1;
+        drop(syncs);
+
+        self.file.sync_data()
+    }
+}
+
+#[test]
+fn durability() {
+    let _ = env_logger::try_init();
+
+    const N: usize = 100;
+
+    let path = Path::new("test13");
+    let wal_path = Path::new("test13-wal");
+    let items = generate_entries(N, 10, 6);
+    // Log syncs expected from N puts
+    let policies = [
+        (Durability::None, 0),
+        (Durability::OnCommit, N),
+        (Durability::Periodic(Duration::ZERO), N),
+        (Durability::Periodic(Duration::from_secs(3600)), 0),
+    ];
+
+    for (durability, expected) in policies {
+        let vfs = Arc::new(SyncCounter::default());
+        let options = Options {
+            durability,
+            vfs: vfs.clone(),
+            ..Options::default()
+        };
+
+        let mut connection = Connection::open_with(path, options.clone()).unwrap();
+        insert_items(&mut connection, &items);
+        assert_eq!(vfs.syncs(wal_path), expected, "{durability:?}");
+
+        // Only what wasn't synced yet is
+        connection.sync().unwrap();
+        assert_eq!(vfs.syncs(wal_path), expected.max(1), "{durability:?}");
+
+        // Closing checkpoints, syncing the database
+        // file before the log is emptied
+        let db_syncs = vfs.syncs(path);
+        drop(connection);
+        assert!(vfs.syncs(path) > db_syncs, "{durability:?}");
+
+        let mut connection = Connection::open_with(path, options).unwrap();
+        let (successful, _) = get_items(&mut connection, &items);
+        drop(connection);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(successful, items.len());
+    }
+}
+
+#[test]
+fn page_reuse() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test15");
+    let items = generate_entries(2000, 10, 6);
+    let large: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+
+    // Emptied and refilled pages, rewritten overflow chains
+    let mut sizes = Vec::new();
+    for _ in 0..3 {
+        let mut connection = Connection::open(path).unwrap();
+        for key in items.keys() {
+            connection.delete(key).unwrap();
+        }
+        insert_items(&mut connection, &items);
+        connection.put(&b"large".to_vec(), &large).unwrap();
+        connection.put(&b"large".to_vec(), &large).unwrap();
+        check_tree(&mut connection);
+        drop(connection);
+
+        sizes.push(std::fs::metadata(path).unwrap().len());
+    }
+
+    let mut connection = Connection::open(path).unwrap();
+    let (successful, _) = get_items(&mut connection, &items);
+    let value = connection.get(&b"large".to_vec()).unwrap();
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(successful, items.len());
+    assert_eq!(value, Some(large));
+    assert_eq!(sizes[0], sizes[2]);
+}
+
+#[test]
+fn vacuum_shrinks_file() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test16");
+    let items = generate_entries(5000, 10, 6);
+    let large: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &items);
+    connection.put(&b"large".to_vec(), &large).unwrap();
+
+    let mut kept = HashMap::new();
+    for (i, (key, value)) in items.iter().enumerate() {
+        if i % 10 == 0 {
+            kept.insert(key.clone(), value.clone());
+        } else {
+            assert!(connection.delete(key).unwrap());
+        }
+    }
+    drop(connection);
+    let size = std::fs::metadata(path).unwrap().len();
+
+    let mut connection = Connection::open(path).unwrap();
+    let reclaimed = connection.vacuum().unwrap();
+    let (successful, _) = get_items(&mut connection, &kept);
+    assert_eq!(successful, kept.len());
+    assert_eq!(connection.get(&b"large".to_vec()).unwrap(), Some(large.clone()));
+    check_tree(&mut connection);
+    drop(connection);
+
+    assert!(reclaimed > 0);
+    assert_eq!(std::fs::metadata(path).unwrap().len(), size - reclaimed);
+    // Down to the pages 500 entries fill and the overflow chain
+    assert!(size - reclaimed <= 12 * 4096);
+    assert_eq!(vacuum(path, Options::default()).unwrap(), 0);
+
+    let mut connection = Connection::open(path).unwrap();
+    let (successful, _) = get_items(&mut connection, &kept);
+    let value = connection.get(&b"large".to_vec()).unwrap();
+    let entries = connection.range(..).unwrap().count();
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(successful, kept.len());
+    assert_eq!(value, Some(large));
+    assert_eq!(entries, kept.len() + 1);
+}
+
+///
+/// Files on the local file system, with writes
+/// to the log failing while the switch is on
+///
+#[derive(Debug, Default)]
+struct LogFailure {
+    failing: Arc<AtomicBool>,
+}
+
+impl Vfs for LogFailure {
+    fn open(&self, path: &Path, create: bool) -> std::io::Result<Box<dyn tinystore::vfs::VfsFile>> {
+        let is_log = path.to_string_lossy().ends_with("-wal");
+        Ok(Box::new(FailingFile {
+            file: OsVfs.open(path, create)?,
+            failing: is_log.then(|| self.failing.clone()),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        OsVfs.remove(path)
+    }
+}
+
+struct FailingFile {
+    file: Box<dyn tinystore::vfs::VfsFile>,
+    failing: Option<Arc<AtomicBool>>,
+}
+
+impl tinystore::vfs::VfsFile for FailingFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        if self
+            .failing
+            .as_ref()
+            .is_some_and(|failing| failing.load(Ordering::Relaxed))
+        {
+            return Err(std::io::Error::other("injected log write failure"));
+        }
+
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        self.file.size()
+    }
+
+    fn sync_data(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[test]
+fn failed_vacuum_keeps_pages() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test16-failed");
+    let items = generate_entries(3000, 10, 6);
+    let vfs = Arc::new(LogFailure::default());
+    let options = Options {
+        vfs: vfs.clone(),
+        ..Options::default()
+    };
+
+    let mut connection = Connection::open_with(path, options.clone()).unwrap();
+    insert_items(&mut connection, &items);
+    let mut kept = HashMap::new();
+    for (i, (key, value)) in items.iter().enumerate() {
+        if i % 10 == 0 {
+            kept.insert(key.clone(), value.clone());
+        } else {
+            assert!(connection.delete(key).unwrap());
+        }
+    }
+    let size = connection.header().size;
+
+    // Fails committing the packed tree, after it's been built
+    vfs.failing.store(true, Ordering::Relaxed);
+    assert!(connection.vacuum().is_err());
+    vfs.failing.store(false, Ordering::Relaxed);
+
+    // Every page is either in the tree or on the free list,
+    // none were taken by the tree that was thrown away
+    let report = connection.check().unwrap();
+    assert!(report.is_ok(), "{:?}", report.violations);
+    assert_eq!(connection.header().size, size);
+    assert_eq!((report.pages + 1) * 4096, size);
+    drop(connection);
+
+    let mut connection = Connection::open_with(path, options).unwrap();
+    let report = connection.check().unwrap();
+    let (successful, _) = get_items(&mut connection, &kept);
+    let entries = connection.range(..).unwrap().count();
+    drop(connection);
+    let file_size = std::fs::metadata(path).unwrap().len();
+    std::fs::remove_file(path).unwrap();
+
+    assert!(report.is_ok(), "{:?}", report.violations);
+    assert_eq!(file_size, size);
+    assert_eq!((report.pages + 1) * 4096, size);
+    assert_eq!(successful, kept.len());
+    assert_eq!(entries, kept.len());
+}
+
+#[test]
+fn checksum_corruption() {
+    use std::os::unix::fs::FileExt;
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test17");
+    let items = generate_entries(2000, 10, 6);
+
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &items);
+    drop(connection);
+
+    // Flip a byte in the middle of the first tree page
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+    let mut byte = [0u8];
+    file.read_exact_at(&mut byte, 4096 + 2048).unwrap();
+    file.write_all_at(&[byte[0] ^ 0xff], 4096 + 2048).unwrap();
+    drop(file);
+
+    let connection = Connection::open(path).unwrap();
+    let result: Result<Vec<_>, _> = connection.range(..).and_then(|entries| entries.collect());
+    let report = connection.check().unwrap();
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert!(matches!(result, Err(Error::Corrupt { page: 1 })));
+    assert!(report.violations.contains(&Violation::Unreadable { page: 1 }));
+}
+
+///
+/// Bitwise crc32c, for tests rewriting checksummed bytes
+///
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F63B78 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+///
+/// Stamps a page with a checksum matching its contents
+///
+fn restamp_page(file: &std::fs::File, pid: u64) {
+    use std::os::unix::fs::FileExt;
+
+    let mut page = vec![0u8; 4096];
+    file.read_exact_at(&mut page, pid * 4096).unwrap();
+    let crc = crc32c(&[&page[..12], &page[16..]].concat());
+    file.write_all_at(&crc.to_be_bytes(), pid * 4096 + 12)
+        .unwrap();
+}
+
+///
+/// Stamps the metadata with a checksum matching its slot
+///
+fn restamp_metadata(file: &std::fs::File) {
+    use std::os::unix::fs::FileExt;
+
+    let mut slot = [0u8; 32];
+    file.read_exact_at(&mut slot, 0).unwrap();
+    file.write_all_at(&crc32c(&slot).to_be_bytes(), 32).unwrap();
+}
+
+#[test]
+fn checksummed_but_corrupt() {
+    use std::os::unix::fs::FileExt;
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test17-slots");
+    let items = generate_entries(2000, 10, 6);
+
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &items);
+    drop(connection);
+
+    // Point the first slot of the first tree page past its end,
+    // with a checksum that still matches
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    file.write_all_at(&4090u16.to_be_bytes(), 4096 + 16)
+        .unwrap();
+    restamp_page(&file, 1);
+    drop(file);
+
+    let connection = Connection::open(path).unwrap();
+    let result: Result<Vec<_>, _> = connection.range(..).and_then(|entries| entries.collect());
+    let report = connection.check().unwrap();
+    drop(connection);
+
+    assert!(matches!(result, Err(Error::Corrupt { page: 1 })));
+    assert!(report
+        .violations
+        .contains(&Violation::BadSlot { page: 1, slot: 0 }));
+
+    // Metadata has a checksum of its own
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut byte = [0u8];
+    file.read_exact_at(&mut byte, 8).unwrap();
+    file.write_all_at(&[byte[0] ^ 0x01], 8).unwrap();
+    drop(file);
+
+    let err = Connection::open(path).err().unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert!(matches!(err, Error::Corrupt { page: 0 }));
+}
+
+#[test]
+fn root_height_mismatch() {
+    use std::os::unix::fs::FileExt;
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test19");
+    let mut connection = Connection::open(path).unwrap();
+    connection.put(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+    drop(connection);
+
+    // magic | version | size | root | height, the
+    // height is the byte after a two page size
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+    file.write_all_at(&[0], 10).unwrap();
+    restamp_metadata(&file);
+    drop(file);
+
+    let connection = Connection::open(path).unwrap();
+    let report = connection.check().unwrap();
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(
+        report.violations,
+        vec![Violation::RootHeightMismatch { root: 1, height: 0 }]
+    );
+}
+
+#[test]
+fn check_without_writing() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test19-pending");
+    let items = generate_entries(200, 10, 6);
+
+    let mut connection = Connection::open(path).unwrap();
+    insert_items(&mut connection, &items);
+
+    // Every commit is still only in the log
+    let lengths = || {
+        let file = std::fs::metadata(path).unwrap().len();
+        let wal = std::fs::metadata("test19-pending-wal").unwrap().len();
+        (file, wal)
+    };
+    let before = lengths();
+    let report = connection.check().unwrap();
+    let after = lengths();
+    drop(connection);
+    std::fs::remove_file(path).unwrap();
+
+    assert!(report.is_ok(), "{:?}", report.violations);
+    assert!(report.pages > 1);
+    assert_eq!(before, after);
+    assert_eq!(before.0, 4096);
+}
+
+///
+/// Page in the original layout: item count, 4 unused bytes
+/// then the offsets, with items packed from the end
+///
+fn v1_page(items: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut page = vec![0u8; 4096];
+    let mut end = page.len();
+    for (i, (key, value)) in items.iter().enumerate() {
+        end -= 4 + key.len() + value.len();
+        page[end..end + 2].copy_from_slice(&(key.len() as u16).to_be_bytes());
+        page[end + 2..end + 4].copy_from_slice(&(value.len() as u16).to_be_bytes());
+        page[end + 4..end + 4 + key.len()].copy_from_slice(key);
+        page[end + 4 + key.len()..end + 4 + key.len() + value.len()].copy_from_slice(value);
+        page[6 + 2 * i..8 + 2 * i].copy_from_slice(&(end as u16).to_be_bytes());
+    }
+
+    page[0..2].copy_from_slice(&(items.len() as u16).to_be_bytes());
+    page
+}
+
+#[test]
+fn format_upgrade() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test18");
+    // Too large to stay inline once upgraded
+    let large: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let mut expected: Vec<(Vec<u8>, Vec<u8>)> = (0..100)
+        .map(|i| (format!("a{i:03}").into_bytes(), format!("v{i}").into_bytes()))
+        .collect();
+    expected.push((b"big".to_vec(), large.clone()));
+
+    // Putting a key again added an entry in front of the old one
+    let mut left = expected[..50].to_vec();
+    left.insert(10, (b"a010".to_vec(), b"newer".to_vec()));
+    let right = expected[50..].to_vec();
+    expected[10].1 = b"newer".to_vec();
+
+    let root = v1_page(&[
+        (b"a049".to_vec(), 1u32.to_be_bytes().to_vec()),
+        (vec![], 2u32.to_be_bytes().to_vec()),
+    ]);
+
+    // magic | size | root | height, all varints
+    let mut header = vec![0u8; 4096];
+    header[..10].copy_from_slice(&[252, 0x54, 0x49, 0x4E, 0x59, 251, 0x40, 0x00, 3, 2]);
+
+    let file = [header, v1_page(&left), v1_page(&right), root].concat();
+    std::fs::write(path, file).unwrap();
+
+    let mut connection = Connection::open(path).unwrap();
+    let entries: Vec<_> = connection.range(..).unwrap().map(|e| e.unwrap()).collect();
+    check_tree(&mut connection);
+    drop(connection);
+    assert_eq!(entries, expected);
+
+    // Reopens as the current version, no upgrade needed
+    let connection = Connection::open(path).unwrap();
+    let value = connection.get(&b"big".to_vec()).unwrap();
+    drop(connection);
+    assert_eq!(value, Some(large));
+
+    // Versions newer than this build are refused
+    let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&file, &[3], 5).unwrap();
+    drop(file);
+    let err = Connection::open(path).err().unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert!(matches!(err, Error::UnsupportedVersion { found: 3, supported: 2 }));
+}
+
+#[test]
+fn dump_pages() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test20");
+    let mut connection = Connection::open(path).unwrap();
+    for i in 0..300 {
+        let key = format!("key{i:03}").into_bytes();
+        connection.put(&key, &format!("value{i}").into_bytes()).unwrap();
+    }
+    connection.put(&b"large".to_vec(), &vec![7u8; 6000]).unwrap();
+    drop(connection);
+
+    let dump = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_tinystore-dump"))
+            .arg(path)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let summary = dump(&[]);
+    let all = dump(&["--all"]);
+    let root_id = summary
+        .lines()
+        .find_map(|line| line.strip_prefix("root"))
+        .unwrap()
+        .trim()
+        .to_string();
+    let root = dump(&["--page", &root_id]);
+    let subtree = dump(&["--subtree", &root_id, "--hex"]);
+
+    assert_eq!(summary.matches("overflow").count(), 2);
+    assert!(all.contains("\"key042\" => \"value42\""));
+    assert!(all.contains("\"large\" => overflow of 6000 bytes"));
+    assert!(root.contains("internal") && root.contains("=> child"));
+    assert!(subtree.contains("0x6b6579303432 => 0x76616c75653432"));
+
+    // Files from before the format upgrade dump as they are
+    let mut header = vec![0u8; 4096];
+    header[..10].copy_from_slice(&[252, 0x54, 0x49, 0x4E, 0x59, 251, 0x20, 0x00, 1, 1]);
+    let leaf = v1_page(&[(b"old".to_vec(), b"layout".to_vec())]);
+    std::fs::write(path, [header, leaf].concat()).unwrap();
+    let v1 = dump(&["--all"]);
+    std::fs::remove_file(path).unwrap();
+
+    assert!(v1.contains("version     1"));
+    assert!(v1.contains("\"old\" => \"layout\""));
+    assert!(!v1.contains("BAD CHECKSUM"));
+}
+
+#[test]
+fn command_line_client() {
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test21");
+    let tinystore = |args: &[&str], stdin: &str| {
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_tinystore"))
+            .arg(path)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        (output.status.code(), String::from_utf8(output.stdout).unwrap())
+    };
+
+    tinystore(&["put", "hello", "world"], "");
+    tinystore(&["put"], "apple\tred\nbanana\tyellow\napricot\torange\n");
+    tinystore(&["put", "-e", "hex", "00ff", "c0ffee"], "");
+    tinystore(&["put", "blob"], "from stdin");
+
+    let get = tinystore(&["get", "hello"], "");
+    let missing = tinystore(&["get", "pear"], "");
+    let prefix = tinystore(&["scan", "--prefix", "ap"], "");
+    let count = tinystore(&["count", "--from", "b", "--to", "i"], "");
+    let binary = tinystore(&["scan", "-e", "base64", "--to", "AQ=="], "");
+    let blob = tinystore(&["get", "-e", "hex", "626c6f62"], "");
+
+    tinystore(&["delete"], "apple\nbanana\n");
+    let deleted = tinystore(&["delete", "apple"], "");
+    let remaining = tinystore(&["count"], "");
+    let stats = tinystore(&["stats"], "");
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(get, (Some(0), "world\n".to_string()));
+    assert_eq!(missing, (Some(1), String::new()));
+    assert_eq!(prefix.1, "apple\tred\napricot\torange\n");
+    assert_eq!(count.1, "3\n");
+    assert_eq!(binary.1, "AP8=\twP/u\n");
+    assert_eq!(blob.1, "66726f6d20737464696e\n");
+    assert_eq!(deleted.0, Some(1));
+    assert_eq!(remaining.1, "4\n");
+    assert!(stats.1.contains("entries         4"));
+    assert!(stats.1.contains("violations      0"));
+}
+
+///
+/// Sends a single request on its own connection,
+/// returning the status line, headers and body
+///
+fn http_request(addr: &str, method: &str, target: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+    use std::io::Read;
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    let head = format!(
+        "{method} {target} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let status = head[9..12].parse().unwrap();
+
+    (status, head, response[split + 4..].to_vec())
+}
+
+#[test]
+fn http_server() {
+    use std::io::BufRead;
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test22");
+    let mut server = std::process::Command::new(env!("CARGO_BIN_EXE_tinystore-server"))
+        .arg(path)
+        .args(["--listen", "127.0.0.1:0", "--max-body", "1024"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut line = String::new();
+    std::io::BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line.trim().strip_prefix("Listening on ").unwrap().to_string();
+    let request = |method: &str, target: &str, body: &[u8]| http_request(&addr, method, target, body);
+
+    let put = request("PUT", "/kv/hello", b"world");
+    let get = request("GET", "/kv/hello", b"");
+    let missing = request("GET", "/kv/missing", b"");
+    for i in 0..5 {
+        request("PUT", &format!("/kv/item%20{i}"), format!("value {i}").as_bytes());
+    }
+    let binary = request("PUT", "/kv/%00%FF", &[1, 2, 3]);
+    let binary_get = request("GET", "/kv/%00%FF", b"");
+    let listing = request("GET", "/kv?prefix=item&start=item%201&limit=2", b"");
+    let all = request("GET", "/kv", b"");
+    let too_large = request("PUT", "/kv/large", &[0u8; 2048]);
+    let deleted = request("DELETE", "/kv/hello", b"");
+    let deleted_again = request("DELETE", "/kv/hello", b"");
+    let not_allowed = request("POST", "/kv/hello", b"");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    std::fs::remove_file(path).unwrap();
+    let _ = std::fs::remove_file("test22-wal");
+
+    assert_eq!(put.0, 204);
+    assert_eq!((get.0, get.2), (200, b"world".to_vec()));
+    assert_eq!(missing.0, 404);
+    assert_eq!(binary.0, 204);
+    assert_eq!(binary_get.2, vec![1, 2, 3]);
+    assert_eq!(listing.0, 200);
+    assert_eq!(listing.2, b"item%201\tvalue%201\nitem%202\tvalue%202\n");
+    assert!(listing.1.contains("X-Next-Start: item%203"));
+    assert_eq!(all.2.split(|&b| b == b'\n').count(), 8);
+    assert_eq!(too_large.0, 413);
+    assert_eq!(deleted.0, 204);
+    assert_eq!(deleted_again.0, 404);
+    assert_eq!(not_allowed.0, 405);
+}
+
+fn resp_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+
+    command
+}
+
+///
+/// Reads the count or length line heading a reply
+///
+fn resp_length(reader: &mut impl std::io::BufRead) -> usize {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+
+    line.trim_end()[1..].parse().unwrap()
+}
+
+///
+/// Reads a bulk string by its length, as it
+/// may hold line breaks of its own
+///
+fn resp_bulk(reader: &mut impl std::io::BufRead) -> Vec<u8> {
+    let mut bulk = vec![0; resp_length(reader) + 2];
+    reader.read_exact(&mut bulk).unwrap();
+    bulk.truncate(bulk.len() - 2);
+
+    bulk
+}
+
+#[test]
+fn resp_server() {
+    use std::io::{BufRead, Read};
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test23");
+    let mut server = std::process::Command::new(env!("CARGO_BIN_EXE_tinystore-server"))
+        .arg(path)
+        .args(["--resp", "--listen", "127.0.0.1:0", "--max-body", "1024"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut line = String::new();
+    std::io::BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line
+        .trim()
+        .strip_prefix("Listening on ")
+        .unwrap()
+        .to_string();
+
+    // Pipelined, so the replies come back in order in one stream
+    let commands: Vec<&[&[u8]]> = vec![
+        &[b"PING"],
+        &[b"SET", b"a", b"1"],
+        &[b"set", b"a", b"2", b"NX"],
+        &[b"MSET", b"b", b"2", b"user:1", b"\x00\xff"],
+        &[b"MGET", b"a", b"missing", b"user:1"],
+        &[b"EXISTS", b"a", b"a", b"missing"],
+        &[b"SCAN", b"0", b"COUNT", b"2"],
+        &[b"SCAN", b"1098", b"COUNT", b"2"],
+        &[b"SCAN", b"0", b"MATCH", b"user:*"],
+        &[b"DEL", b"a", b"b", b"missing"],
+        // Resumes after a, even though it's gone
+        &[b"SCAN", b"1097", b"COUNT", b"2"],
+        &[b"SCAN", b"1256"],
+        &[b"GET", b"a"],
+        &[b"GET"],
+        &[b"FLUSHALL"],
+        &[b"QUIT"],
+    ];
+    let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+    stream.write_all(b"PING hello\r\n").unwrap();
+    for command in commands {
+        stream.write_all(&resp_command(command)).unwrap();
+    }
+    let mut replies = Vec::new();
+    stream.read_to_end(&mut replies).unwrap();
+
+    let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+    stream.write_all(b"*1\r\n$2048\r\n").unwrap();
+    let mut too_large = Vec::new();
+    stream.read_to_end(&mut too_large).unwrap();
+
+    // Cursors go through an integer and back, the
+    // way clients such as redis-py pass them on
+    let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    stream
+        .write_all(&resp_command(&[b"MSET", b"\x0a\xff", b"1", b"\x0a", b"2"]))
+        .unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "+OK\r\n");
+    let mut scanned = Vec::new();
+    let mut cursor = 0u64;
+    loop {
+        let arg = cursor.to_string();
+        stream
+            .write_all(&resp_command(&[b"SCAN", arg.as_bytes(), b"COUNT", b"1"]))
+            .unwrap();
+        assert_eq!(resp_length(&mut reader), 2);
+        let next = resp_bulk(&mut reader);
+        assert_eq!(resp_length(&mut reader), 1);
+        scanned.push(resp_bulk(&mut reader));
+
+        cursor = std::str::from_utf8(&next).unwrap().parse().unwrap();
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    std::fs::remove_file(path).unwrap();
+    let _ = std::fs::remove_file("test23-wal");
+
+    let expected: &[&[u8]] = &[
+        b"$5\r\nhello\r\n",
+        b"+PONG\r\n",
+        b"+OK\r\n",
+        b"$-1\r\n",
+        b"+OK\r\n",
+        b"*3\r\n$1\r\n1\r\n$-1\r\n$2\r\n\x00\xff\r\n",
+        b":2\r\n",
+        b"*2\r\n$4\r\n1098\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:1\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:1\r\n",
+        b":2\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:1\r\n",
+        b"-ERR invalid cursor\r\n",
+        b"$-1\r\n",
+        b"-ERR wrong number of arguments for 'get' command\r\n",
+        b"-ERR unknown command 'FLUSHALL'\r\n",
+        b"+OK\r\n",
+    ];
+    assert_eq!(
+        String::from_utf8_lossy(&replies),
+        String::from_utf8_lossy(&expected.concat())
+    );
+    assert!(too_large.starts_with(b"-ERR Protocol error"));
+    let keys: [&[u8]; 3] = [b"\x0a", b"\x0a\xff", b"user:1"];
+    assert_eq!(scanned, keys);
+}
+
+#[test]
+fn shared_database() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Database>();
+
+    const WRITERS: usize = 4;
+    const READERS: usize = 4;
+    const N: usize = 1000;
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test24");
+    let db = Database::open(path).unwrap();
+    let value = |key: &[u8], i: usize| match i % 10 {
+        // Some values long enough to overflow
+        0 => key.repeat(1000),
+        _ => key.to_vec(),
+    };
+
+    std::thread::scope(|scope| {
+        for w in 0..WRITERS {
+            let db = db.clone();
+            scope.spawn(move || {
+                for i in 0..N {
+                    let key = format!("{w}-{i:05}").into_bytes();
+                    db.put(&key, &value(&key, i)).unwrap();
+                }
+            });
+        }
+
+        // Both counters change in a single transaction
+        let counters = db.clone();
+        scope.spawn(move || {
+            for i in 0..N as u64 {
+                let mut connection = counters.write();
+                let mut txn = connection.begin();
+                txn.put(&b"counter a".to_vec(), &i.to_be_bytes().to_vec())
+                    .unwrap();
+                txn.put(&b"counter b".to_vec(), &i.to_be_bytes().to_vec())
+                    .unwrap();
+                txn.commit().unwrap();
+            }
+        });
+
+        for r in 0..READERS {
+            let db = db.clone();
+            scope.spawn(move || {
+                for i in 0..N {
+                    let key = format!("{}-{i:05}", (r + i) % WRITERS).into_bytes();
+                    if let Some(found) = db.get(&key).unwrap() {
+                        assert_eq!(found, value(&key, i));
+                    }
+
+                    let connection = db.read();
+                    let a = connection.get(&b"counter a".to_vec()).unwrap();
+                    let b = connection.get(&b"counter b".to_vec()).unwrap();
+                    assert_eq!(a, b);
+
+                    // Each writer's keys land in order, so a scan
+                    // sees the first however many of them
+                    let prefix = format!("{r}-").into_bytes();
+                    let keys: Vec<Vec<u8>> = connection
+                        .scan_prefix(&prefix)
+                        .unwrap()
+                        .map(|entry| entry.unwrap().0)
+                        .collect();
+                    for (i, key) in keys.iter().enumerate() {
+                        assert_eq!(key, &format!("{r}-{i:05}").into_bytes());
+                    }
+                }
+            });
+        }
+    });
+
+    for w in 0..WRITERS {
+        for i in 0..N {
+            let key = format!("{w}-{i:05}").into_bytes();
+            assert_eq!(db.get(&key).unwrap(), Some(value(&key, i)));
+        }
+    }
+    check_tree(&mut db.write());
+
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn concurrent_inserts() {
+    use rand::Rng;
+
+    const THREADS: usize = 8;
+    const N: usize = 2000;
+
+    let _ = env_logger::try_init();
+
+    let path = Path::new("test25");
+    let options = Options {
+        cache_capacity: 64,
+        durability: Durability::None,
+        ..Options::default()
+    };
+    let db = Database::open_with(path, options).unwrap();
+
+    let written: Vec<BTreeMap<Vec<u8>, Vec<u8>>> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let db = db.clone();
+                scope.spawn(move || {
+                    let mut rng = rand::rng();
+                    let mut written = BTreeMap::new();
+                    let mut keys: Vec<Vec<u8>> = Vec::new();
+                    for i in 0..N {
+                        // Random keys spread every thread across the
+                        // whole tree, the suffix keeps them disjoint
+                        let key = match i % 8 {
+                            7 => keys[rng.random_range(0..keys.len())].clone(),
+                            _ => format!("{}-{t}", Alphanumeric.sample_string(&mut rng, 12))
+                                .into_bytes(),
+                        };
+                        let value = match i % 20 {
+                            // Some values long enough to overflow
+                            0 => key.repeat(rng.random_range(100..1000)),
+                            _ => key.repeat(rng.random_range(1..10)),
+                        };
+
+                        db.put(&key, &value).unwrap();
+                        written.insert(key.clone(), value);
+                        keys.push(key);
+                    }
+                    written
+                })
+            })
+            .collect();
+
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    });
+
+    let oracle: BTreeMap<Vec<u8>, Vec<u8>> = written.into_iter().flatten().collect();
+    let found: BTreeMap<Vec<u8>, Vec<u8>> = db
+        .read()
+        .range::<std::ops::RangeFull>(..)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert!(found == oracle, "scan differs from what was written");
+    for (key, value) in &oracle {
+        assert_eq!(db.get(key).unwrap().as_ref(), Some(value));
+    }
+    check_tree(&mut db.write());
+
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}